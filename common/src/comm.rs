@@ -1,23 +1,149 @@
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fmt;
 
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
 use tokio_tungstenite::tungstenite::Message;
 use tokio_tungstenite::WebSocketStream;
 
 use futures_util::stream::{SplitSink, SplitStream};
+use futures_util::SinkExt;
 
 use hyper::upgrade::Upgraded;
+use log::info;
+use tokio::sync::{mpsc, oneshot, watch};
+use tokio::task::JoinHandle;
+
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::limit::ConnGuard;
+
+// Pings and closes from the player handler, which never sends many
+const CONTROL_QUEUE: usize = 4;
 
 /// Version 3
 /// The game has full control of the comms
+/// Messages are queued to a writer task, so a slow player never blocks the game.
 #[derive(Debug)]
 pub struct PlayerSink {
     pub id: u32,
-    pub sink: SplitSink<WebSocketStream<Upgraded>, Message>,
+    // Unique per websocket, differs from the id once a player resumed.
+    pub conn: u32,
+    pub meta: PlayerMeta,
+    // Set once the outgoing queue overflowed, until a message fits again
+    pub lagging: bool,
+    queue: mpsc::Sender<Message>,
+    // Control frames, sent before the queued messages
+    control: mpsc::Sender<Message>,
+    writer: JoinHandle<()>,
+    // The writer holds the sender, dropped once the socket is done
+    alive: watch::Receiver<()>,
 }
 impl PlayerSink {
     pub fn new(id: u32, sink: SplitSink<WebSocketStream<Upgraded>, Message>) -> Self {
-        Self { id, sink }
+        let (queue, rx) = mpsc::channel(CONFIG.player_outgoing);
+        let (control, control_rx) = mpsc::channel(CONTROL_QUEUE);
+        let (alive_tx, alive) = watch::channel(());
+        let writer = tokio::spawn(async move {
+            if let Err(e) = write_player(sink, control_rx, rx).await {
+                info!("Player {} failed: {}", id, e);
+            }
+            drop(alive_tx);
+        });
+        Self {
+            id,
+            conn: id,
+            meta: PlayerMeta::new(),
+            lagging: false,
+            queue,
+            control,
+            writer,
+            alive,
+        }
+    }
+
+    /// Queues a message, false if the queue is full.
+    pub fn send(&self, msg: Message) -> bool {
+        self.queue.try_send(msg).is_ok()
+    }
+
+    /// Closes the websocket once the queued messages are sent.
+    pub fn close(&self, msg: Option<CloseFrame<'static>>) {
+        if !self.send(Message::Close(msg)) {
+            self.kill();
+        }
+    }
+
+    /// Sends messages from outside the game (i.e. pings) ahead of the queue.
+    pub fn outgoing(&self) -> mpsc::Sender<Message> {
+        self.control.clone()
+    }
+
+    /// Drops the websocket right away, without waiting for the queue.
+    pub fn kill(&self) {
+        self.writer.abort();
+    }
+
+    /// Errors on `changed()` once the socket failed, was closed or killed.
+    pub fn alive(&self) -> watch::Receiver<()> {
+        self.alive.clone()
+    }
+}
+
+async fn write_player(
+    mut sink: SplitSink<WebSocketStream<Upgraded>, Message>,
+    mut control: mpsc::Receiver<Message>,
+    mut rx: mpsc::Receiver<Message>,
+) -> Result<(), Error> {
+    loop {
+        // A ping waiting behind the queue would measure the queue, not the player
+        let msg = tokio::select! {
+            biased;
+            Some(msg) = control.recv() => msg,
+            msg = rx.recv() => match msg {
+                Some(msg) => msg,
+                None => break,
+            },
+        };
+        let close = msg.is_close();
+        sink.send(msg).await?;
+        if close {
+            break;
+        }
     }
+    sink.close().await?;
+    Ok(())
+}
+
+/// Free form player information, i.e. `/ROOM?name=Ana&avatar=3`
+pub type PlayerMeta = BTreeMap<String, String>;
+
+/// Players play, the audience watches (and may vote) without taking a seat.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum PlayerRole {
+    #[default]
+    Player,
+    Audience,
+}
+
+/// Options a player provides in the query string, i.e. `/ROOM?resume=TOKEN&name=Ana`
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct JoinParams {
+    // Some("") asks for a resumable session, Some(token) resumes it.
+    pub resume: Option<String>,
+    pub role: PlayerRole,
+    // Every parameter that is not reserved, forwarded to the host
+    pub meta: PlayerMeta,
+    // Checked against the password of the room, never forwarded
+    pub password: Option<String>,
+}
+
+#[derive(Debug)]
+pub struct PlayerJoin {
+    pub sink: PlayerSink,
+    pub params: JoinParams,
+    // Receives the player id once admitted, dropped if refused.
+    pub admitted: oneshot::Sender<u32>,
 }
 
 #[derive(Debug)]
@@ -43,31 +169,278 @@ impl Player {
     }
 }
 
-#[derive(Serialize, Deserialize, Debug, PartialEq, Eq)]
+/// Wire format of the commands exchanged with the host
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Encoding {
+    #[default]
+    Cbor,
+    Json,
+    Msgpack,
+}
+
+impl Encoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Encoding::Cbor => "cbor",
+            Encoding::Json => "json",
+            Encoding::Msgpack => "msgpack",
+        }
+    }
+}
+
+/// Versions of the protocol this server speaks, the last one is the newest.
+/// v2: `State` lists the players as `PlayerInfo` instead of ids.
+pub const PROTOCOL_VERSIONS: &[u32] = &[1, 2];
+
+/// Websocket subprotocol negotiated with `Sec-WebSocket-Protocol`,
+/// named `wmbp.v1` or `wmbp.v1.json` to also pick the host encoding.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Protocol {
+    pub version: u32,
+    pub encoding: Option<Encoding>,
+}
+
+impl Protocol {
+    pub fn parse(name: &str) -> Option<Self> {
+        let mut parts = name.trim().split('.');
+        if parts.next()? != "wmbp" {
+            return None;
+        }
+        let version: u32 = parts.next()?.strip_prefix('v')?.parse().ok()?;
+        let encoding = match parts.next() {
+            None => None,
+            Some("cbor") => Some(Encoding::Cbor),
+            Some("json") => Some(Encoding::Json),
+            Some("msgpack") => Some(Encoding::Msgpack),
+            Some(_) => return None,
+        };
+        if parts.next().is_some() || !PROTOCOL_VERSIONS.contains(&version) {
+            return None;
+        }
+        Some(Self { version, encoding })
+    }
+
+    // Every protocol name we accept, to advertise them
+    pub fn supported() -> Vec<String> {
+        let mut names = vec![];
+        for version in PROTOCOL_VERSIONS.iter().rev() {
+            for encoding in [Encoding::Cbor, Encoding::Json, Encoding::Msgpack] {
+                names.push(format!("wmbp.v{}.{}", version, encoding.as_str()));
+            }
+        }
+        names
+    }
+}
+
+impl fmt::Display for Protocol {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "wmbp.v{}", self.version)?;
+        if let Some(encoding) = self.encoding {
+            write!(f, ".{}", encoding.as_str())?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct PlayerInfo {
+    pub id: u32,
+    pub meta: PlayerMeta,
+}
+
+// The players of a State, depending on the protocol version
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(untagged)]
+pub enum PlayerList {
+    Ids(Vec<u32>),
+    Info(Vec<PlayerInfo>),
+}
+
+/// Why a command of the host failed, for SDKs to react on
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    InvalidFrame,
+    UnknownCommand,
+    InvalidCommand,
+    NotPrepared,
+    AlreadyStarted,
+    PlayerNotFound,
+    RoomFull,
+    RoomKeyUnavailable,
+    InvalidRoomCode,
+    RoomCodeTaken,
+    RoomCodeReserved,
+    GroupNotFound,
+    InvalidGroupName,
+    RateLimited,
+    PayloadTooLarge,
+}
+
+impl ErrorCode {
+    pub fn reason(&self) -> &'static str {
+        match self {
+            ErrorCode::InvalidFrame => "Invalid message.",
+            ErrorCode::UnknownCommand => "Unknown command.",
+            ErrorCode::InvalidCommand => "Invalid arguments for the command.",
+            ErrorCode::NotPrepared => "The room is not prepared yet.",
+            ErrorCode::AlreadyStarted => "The game already started.",
+            ErrorCode::PlayerNotFound => "No such player in the room.",
+            ErrorCode::RoomFull => "The room is full or already started.",
+            ErrorCode::RoomKeyUnavailable => "Failed to find a unique room key",
+            ErrorCode::InvalidRoomCode => "The room code does not fit the format of the server.",
+            ErrorCode::RoomCodeTaken => "The room code is already taken.",
+            ErrorCode::RoomCodeReserved => "The room code is reserved.",
+            ErrorCode::GroupNotFound => "No such group in the room.",
+            ErrorCode::InvalidGroupName => "Group names are up to 32 letters, digits, _ or -.",
+            ErrorCode::RateLimited => "Too many messages, slow down.",
+            ErrorCode::PayloadTooLarge => "The message is too large.",
+        }
+    }
+}
+
+/// Why the server is about to close a room
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ExpiryReason {
+    Idle,
+    Lifetime,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
 #[serde(tag = "cmd")]
 #[serde(rename_all = "snake_case")]
 pub enum Command {
     Prepare {
         max_players: u32,
         name: String,
+        // Encoding of the messages sent to the host from now on
+        #[serde(default)]
+        encoding: Option<Encoding>,
+        // Spectators allowed on top of the players, none by default
+        #[serde(default)]
+        max_spectators: u32,
+        // A specific room code instead of a random one, i.e. "PIZZ"
+        #[serde(default)]
+        code: Option<String>,
+        // Needed for codes reserved by the server
+        #[serde(default)]
+        code_secret: Option<String>,
+        // Players have to join with /ROOM?password=...
+        #[serde(default)]
+        password: Option<String>,
+        // New players wait for Admit or Reject
+        #[serde(default)]
+        approval: bool,
     }, // Prepares a game
     PrepareReply {
-        key: String, // The game key
+        key: String,    // The game key
+        secret: String, // Allows the host to resume the room at /RESUME/ROOM
     },
     PlayerJoined {
         // A player joined
         player: u32,
+        #[serde(default)]
+        meta: PlayerMeta,
     },
     PlayerLeft {
         // A player left
         player: u32,
     },
+    JoinRequest {
+        // A player waits for Admit or Reject, in approval mode
+        player: u32,
+        #[serde(default)]
+        meta: PlayerMeta,
+    },
+    // A player left while waiting for Admit or Reject
+    JoinCancelled {
+        player: u32,
+    },
+    Admit {
+        player: u32,
+    },
+    Reject {
+        player: u32,
+    },
+    PlayerDisconnected {
+        // A resumable player lost its connection, it may come back
+        player: u32,
+    },
+    PlayerReconnected {
+        // A resumable player is back with the same id
+        player: u32,
+    },
+    SpectatorJoined {
+        spectator: u32,
+        #[serde(default)]
+        meta: PlayerMeta,
+    },
+    SpectatorLeft {
+        spectator: u32,
+    },
+    // The room closes in `secs` unless there is some traffic (for idle rooms)
+    Expiring {
+        reason: ExpiryReason,
+        secs: u32,
+    },
+    // Someone tried to join with a wrong password, `attempts` from its address in the current window
+    PasswordFailed {
+        attempts: u32,
+    },
+    // Round trip time of the last ping to a player
+    Latency {
+        player: u32,
+        ms: u32,
+    },
+    // The game sends faster than the player reads, messages are skipped
+    Lagging {
+        player: u32,
+    },
+    // A player sends above its rate limit (or the room's), until it slows down.
+    // `code` is always `rate_limited`, as in Error.
+    RateLimited {
+        player: u32,
+        code: ErrorCode,
+    },
+    // A player sent a message above the size limit and was disconnected.
+    // `code` is always `payload_too_large`, as in Error.
+    TooLarge {
+        player: u32,
+        code: ErrorCode,
+    },
+    // Messages of a player were dropped because it sent too fast
+    Dropped {
+        player: u32,
+        count: u32,
+    },
+    // Sent to a resumable player (in JSON) as first message
+    Session {
+        player: u32,
+        token: String,
+    },
     Start, // Prevent players to join from this point on
     State {
         name: String,
-        players: Vec<u32>,
+        players: PlayerList,
         max_players: u32,
         accept_conns: bool,
+        #[serde(default)]
+        spectators: Vec<u32>,
+        #[serde(default)]
+        max_spectators: u32,
+        #[serde(default)]
+        groups: BTreeMap<String, Vec<u32>>,
+        // Players waiting for Admit or Reject
+        #[serde(default)]
+        pending: Vec<u32>,
+    },
+    // Opens or closes the room to new players, i.e. between rounds
+    Admission {
+        open: bool,
+        #[serde(default)]
+        max_players: Option<u32>,
     },
     Kick {
         player: u32,
@@ -83,22 +456,179 @@ pub enum Command {
         data: String,
     },
     // Data from the game, forwarded to the user
+    // An empty `to` is every player unless `group` or `audience` is set,
+    // `group` adds its members, `audience` adds every spectator and `except` is never sent to
     To {
         to: Vec<u32>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        audience: bool,
+        #[serde(default)]
+        except: Vec<u32>,
         data: Vec<u8>,
     },
     ToStr {
         to: Vec<u32>,
+        #[serde(default)]
+        group: Option<String>,
+        #[serde(default)]
+        audience: bool,
+        #[serde(default)]
+        except: Vec<u32>,
         data: String,
     },
+    // Named groups of players (or spectators) to address with To and ToStr
+    SetGroup {
+        group: String,
+        players: Vec<u32>,
+    },
+    AddToGroup {
+        group: String,
+        players: Vec<u32>,
+    },
+    RemoveFromGroup {
+        group: String,
+        players: Vec<u32>,
+    },
+    DeleteGroup {
+        group: String,
+    },
+    // Lets players send frames to other players, an empty `players` is everyone
+    AllowRelay {
+        players: Vec<u32>,
+        // The host receives the relayed data, not only who sent to whom
+        #[serde(default)]
+        copy: bool,
+    },
+    DenyRelay {
+        players: Vec<u32>,
+    },
+    // A player relayed data to other players, `data` is set if copy was asked
+    Relayed {
+        from: u32,
+        to: Vec<u32>,
+        data: Option<Vec<u8>>,
+    },
+    RelayedStr {
+        from: u32,
+        to: Vec<u32>,
+        data: Option<String>,
+    },
+    // Answers a command with a req_id, `missing` are the recipients which were not there
+    // and `dropped` the ones lagging too much to receive it
+    Ack {
+        req_id: u32,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        missing: Vec<u32>,
+        #[serde(default, skip_serializing_if = "Vec::is_empty")]
+        dropped: Vec<u32>,
+    },
+    // A command with a req_id failed
+    Nack {
+        req_id: u32,
+        code: ErrorCode,
+        reason: String,
+    },
     Error {
+        code: ErrorCode,
         reason: String,
+        // Name of the offending command, if it could be read
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        command: Option<String>,
     },
 }
 
+/// The `cmd` of every command a host may send, the others come from the server.
+pub const HOST_COMMANDS: &[&str] = &[
+    "prepare",
+    "start",
+    "admission",
+    "admit",
+    "reject",
+    "kick",
+    "stop",
+    "to",
+    "to_str",
+    "set_group",
+    "add_to_group",
+    "remove_from_group",
+    "delete_group",
+    "allow_relay",
+    "deny_relay",
+];
+
+/// A command of the host, optionally with an id to be acknowledged
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct HostRequest {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub req_id: Option<u32>,
+    #[serde(flatten)]
+    pub command: Command,
+}
+
+impl Command {
+    pub fn error(code: ErrorCode, command: Option<&str>) -> Self {
+        Command::Error {
+            code,
+            reason: code.reason().to_owned(),
+            command: command.map(str::to_owned),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub enum HostComm {
-    Join(PlayerSink),
-    Leave(u32),
+    Join(PlayerJoin),
+    Leave(u32, u32), // (player, conn)
+    Resume(
+        Box<WebSocketStream<Upgraded>>,
+        String,
+        Option<Protocol>,
+        Option<ConnGuard>,
+    ), // (host, secret, protocol, guard)
     Command(Command),
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn protocol_names() {
+        let v2 = |encoding| {
+            Some(Protocol {
+                version: 2,
+                encoding,
+            })
+        };
+        assert_eq!(Protocol::parse("wmbp.v2"), v2(None));
+        assert_eq!(Protocol::parse("wmbp.v2.json"), v2(Some(Encoding::Json)));
+        assert_eq!(Protocol::parse("wmbp.v2.cbor"), v2(Some(Encoding::Cbor)));
+        assert_eq!(
+            Protocol::parse("wmbp.v2.msgpack"),
+            v2(Some(Encoding::Msgpack))
+        );
+        assert_eq!(Protocol::parse(" wmbp.v2 "), v2(None));
+    }
+
+    #[test]
+    fn protocol_invalid() {
+        assert_eq!(Protocol::parse(""), None);
+        assert_eq!(Protocol::parse("wmbp"), None);
+        assert_eq!(Protocol::parse("wmbp."), None);
+        assert_eq!(Protocol::parse("wmbp.2"), None);
+        assert_eq!(Protocol::parse("wmbp.v99"), None);
+        assert_eq!(Protocol::parse("wmbp.v2.xml"), None);
+        assert_eq!(Protocol::parse("wmbp.v2.json.x"), None);
+        assert_eq!(Protocol::parse("other.v2"), None);
+    }
+
+    #[test]
+    fn supported_protocols_parse() {
+        for name in Protocol::supported() {
+            let protocol = Protocol::parse(&name).unwrap();
+            assert_eq!(protocol.to_string(), name);
+        }
+    }
+}
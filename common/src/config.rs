@@ -0,0 +1,357 @@
+use std::collections::HashMap;
+use std::env;
+use std::net::IpAddr;
+use std::str::FromStr;
+use std::time::Duration;
+
+use log::info;
+use once_cell::sync::Lazy;
+
+/// What to do with a player sending faster than its room can handle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum QueuePolicy {
+    DropOldest,
+    DropNewest,
+    Disconnect,
+}
+
+impl FromStr for QueuePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop_oldest" => Ok(QueuePolicy::DropOldest),
+            "drop_newest" => Ok(QueuePolicy::DropNewest),
+            "disconnect" => Ok(QueuePolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with a player reading slower than the game sends
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SlowPolicy {
+    Skip,
+    Notify,
+    Disconnect,
+}
+
+impl FromStr for SlowPolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "skip" => Ok(SlowPolicy::Skip),
+            "notify" => Ok(SlowPolicy::Notify),
+            "disconnect" => Ok(SlowPolicy::Disconnect),
+            _ => Err(()),
+        }
+    }
+}
+
+/// What to do with a player sending above its rate limit
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RatePolicy {
+    Drop,
+    Throttle,
+    Kick,
+}
+
+impl FromStr for RatePolicy {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drop" => Ok(RatePolicy::Drop),
+            "throttle" => Ok(RatePolicy::Throttle),
+            "kick" => Ok(RatePolicy::Kick),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Server wide settings, read once from the environment.
+pub static CONFIG: Lazy<ServerConfig> = Lazy::new(ServerConfig::from_env);
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// How long a disconnected player can come back with its resume token.
+    pub resume_grace: Duration,
+    /// How long a room survives without its host, waiting for /RESUME/ROOM.
+    pub host_grace: Duration,
+    /// Commands kept for the host while it is away, oldest dropped first.
+    pub host_buffer: usize,
+    /// Upper bound of the spectators a room can ask for in Prepare.
+    pub max_spectators: u32,
+    /// Messages waiting for the game loop of a room.
+    pub room_queue: usize,
+    /// Messages of a single player waiting for room in the room queue.
+    pub player_queue: usize,
+    pub queue_policy: QueuePolicy,
+    /// Messages waiting to be written to a player before it is lagging.
+    pub player_outgoing: usize,
+    pub slow_policy: SlowPolicy,
+    /// How often players are pinged, zero disables it.
+    pub ping_interval: Duration,
+    /// How long a player has to answer a ping before being dropped.
+    pub pong_timeout: Duration,
+    /// Same as ping_interval for the host.
+    pub host_ping_interval: Duration,
+    /// Same as pong_timeout for the host, which then goes away.
+    pub host_pong_timeout: Duration,
+    /// How long a room survives without traffic from host or players, zero disables it.
+    pub room_idle: Duration,
+    /// Hard limit on the lifetime of a room, zero disables it.
+    pub room_lifetime: Duration,
+    /// How long before closing a room its host is warned.
+    pub room_warning: Duration,
+    /// Room codes are made of room_code_length characters from the alphabet.
+    pub room_code_length: usize,
+    pub room_code_alphabet: Vec<char>,
+    /// Random codes tried before giving up on creating a room.
+    pub room_code_retries: u32,
+    /// Words generated codes never contain, uppercase.
+    pub room_code_blocklist: Vec<String>,
+    /// Codes only granted to hosts giving their secret in Prepare.
+    pub reserved_codes: HashMap<String, String>,
+    /// Wrong passwords accepted per client address and room in join_attempts_window,
+    /// joining is refused after. Zero is unlimited.
+    pub join_attempts: usize,
+    pub join_attempts_window: Duration,
+    /// Messages and bytes per second a player may send, zero is unlimited.
+    pub player_msg_rate: u32,
+    pub player_byte_rate: u32,
+    /// Same for all the players of a room together.
+    pub room_msg_rate: u32,
+    pub room_byte_rate: u32,
+    pub rate_policy: RatePolicy,
+    /// Largest message and frame the host may send, in bytes.
+    pub host_max_message: usize,
+    pub host_max_frame: usize,
+    /// Largest message and frame a player may send, in bytes.
+    pub player_max_message: usize,
+    pub player_max_frame: usize,
+    /// Open host websockets per client address, zero is unlimited.
+    pub max_conns_per_ip: u32,
+    /// Rooms a client address may create per minute, zero is unlimited.
+    pub max_creates_per_ip: u32,
+    /// Rooms on the server, zero is unlimited.
+    pub max_rooms: usize,
+    /// Proxies whose X-Forwarded-For header is trusted for the client address.
+    pub trusted_proxies: Vec<IpAddr>,
+    /// Origins (i.e. `https://game.example`) browsers may connect and fetch rooms from, empty allows any.
+    pub allowed_origins: Vec<String>,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            resume_grace: Duration::from_secs(30),
+            host_grace: Duration::from_secs(30),
+            host_buffer: 256,
+            max_spectators: 100,
+            room_queue: 1024,
+            player_queue: 64,
+            queue_policy: QueuePolicy::DropOldest,
+            player_outgoing: 256,
+            slow_policy: SlowPolicy::Notify,
+            ping_interval: Duration::from_secs(15),
+            pong_timeout: Duration::from_secs(10),
+            host_ping_interval: Duration::from_secs(15),
+            host_pong_timeout: Duration::from_secs(10),
+            room_idle: Duration::from_secs(30 * 60),
+            room_lifetime: Duration::from_secs(12 * 60 * 60),
+            room_warning: Duration::from_secs(60),
+            room_code_length: 4,
+            room_code_alphabet: ('A'..='Z').collect(),
+            room_code_retries: 4,
+            room_code_blocklist: vec![],
+            reserved_codes: HashMap::new(),
+            join_attempts: 5,
+            join_attempts_window: Duration::from_secs(60),
+            player_msg_rate: 0,
+            player_byte_rate: 0,
+            room_msg_rate: 0,
+            room_byte_rate: 0,
+            rate_policy: RatePolicy::Drop,
+            host_max_message: 16 << 20,
+            host_max_frame: 16 << 20,
+            player_max_message: 64 << 10,
+            player_max_frame: 64 << 10,
+            max_conns_per_ip: 32,
+            max_creates_per_ip: 10,
+            max_rooms: 10000,
+            trusted_proxies: vec![],
+            allowed_origins: vec![],
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn from_env() -> Self {
+        let default = Self::default();
+        let mut config = Self {
+            resume_grace: env_secs("WMBP_RESUME_GRACE_SECS", default.resume_grace),
+            host_grace: env_secs("WMBP_HOST_GRACE_SECS", default.host_grace),
+            host_buffer: env_or("WMBP_HOST_BUFFER", default.host_buffer),
+            max_spectators: env_or("WMBP_MAX_SPECTATORS", default.max_spectators),
+            room_queue: env_or("WMBP_ROOM_QUEUE", default.room_queue).max(1),
+            player_queue: env_or("WMBP_PLAYER_QUEUE", default.player_queue).max(1),
+            queue_policy: env_or("WMBP_QUEUE_POLICY", default.queue_policy),
+            player_outgoing: env_or("WMBP_PLAYER_OUTGOING", default.player_outgoing).max(1),
+            slow_policy: env_or("WMBP_SLOW_POLICY", default.slow_policy),
+            ping_interval: env_secs("WMBP_PING_INTERVAL_SECS", default.ping_interval),
+            pong_timeout: env_secs("WMBP_PONG_TIMEOUT_SECS", default.pong_timeout),
+            host_ping_interval: env_secs(
+                "WMBP_HOST_PING_INTERVAL_SECS",
+                default.host_ping_interval,
+            ),
+            host_pong_timeout: env_secs("WMBP_HOST_PONG_TIMEOUT_SECS", default.host_pong_timeout),
+            room_idle: env_secs("WMBP_ROOM_IDLE_SECS", default.room_idle),
+            room_lifetime: env_secs("WMBP_ROOM_LIFETIME_SECS", default.room_lifetime),
+            room_warning: env_secs("WMBP_ROOM_WARNING_SECS", default.room_warning),
+            room_code_length: env_or("WMBP_ROOM_CODE_LENGTH", default.room_code_length).max(1),
+            room_code_alphabet: env_alphabet("WMBP_ROOM_CODE_ALPHABET", default.room_code_alphabet),
+            room_code_retries: env_or("WMBP_ROOM_CODE_RETRIES", default.room_code_retries).max(1),
+            room_code_blocklist: env_list("WMBP_ROOM_CODE_BLOCKLIST", default.room_code_blocklist)
+                .into_iter()
+                .map(|word| word.to_uppercase())
+                .collect(),
+            // `PIZZ:secret,TACO:other`
+            reserved_codes: env_list("WMBP_RESERVED_CODES", vec![])
+                .iter()
+                .filter_map(|item| item.split_once(':'))
+                .map(|(code, secret)| (code.to_owned(), secret.to_owned()))
+                .collect(),
+            join_attempts: env_or("WMBP_JOIN_ATTEMPTS", default.join_attempts),
+            join_attempts_window: env_secs(
+                "WMBP_JOIN_ATTEMPTS_WINDOW_SECS",
+                default.join_attempts_window,
+            ),
+            player_msg_rate: env_or("WMBP_PLAYER_MSG_RATE", default.player_msg_rate),
+            player_byte_rate: env_or("WMBP_PLAYER_BYTE_RATE", default.player_byte_rate),
+            room_msg_rate: env_or("WMBP_ROOM_MSG_RATE", default.room_msg_rate),
+            room_byte_rate: env_or("WMBP_ROOM_BYTE_RATE", default.room_byte_rate),
+            rate_policy: env_or("WMBP_RATE_POLICY", default.rate_policy),
+            host_max_message: env_or("WMBP_HOST_MAX_MESSAGE", default.host_max_message),
+            host_max_frame: env_or("WMBP_HOST_MAX_FRAME", default.host_max_frame),
+            player_max_message: env_or("WMBP_PLAYER_MAX_MESSAGE", default.player_max_message),
+            player_max_frame: env_or("WMBP_PLAYER_MAX_FRAME", default.player_max_frame),
+            max_conns_per_ip: env_or("WMBP_MAX_CONNS_PER_IP", default.max_conns_per_ip),
+            max_creates_per_ip: env_or("WMBP_MAX_CREATES_PER_IP", default.max_creates_per_ip),
+            max_rooms: env_or("WMBP_MAX_ROOMS", default.max_rooms),
+            trusted_proxies: env_list("WMBP_TRUSTED_PROXIES", vec![])
+                .iter()
+                .filter_map(|ip| ip.parse().ok())
+                .collect(),
+            allowed_origins: env_list("WMBP_ALLOWED_ORIGINS", default.allowed_origins)
+                .into_iter()
+                .map(|origin| origin.trim_end_matches('/').to_ascii_lowercase())
+                .collect(),
+        };
+        // Reserved codes are written like generated ones, i.e. `pizz` is `PIZZ` by default
+        config.reserved_codes = std::mem::take(&mut config.reserved_codes)
+            .into_iter()
+            .filter_map(
+                |(code, secret)| match fit_alphabet(&code, &config.room_code_alphabet) {
+                    Some(fit) if fit.chars().count() == config.room_code_length => {
+                        Some((fit, secret))
+                    }
+                    _ => {
+                        info!(
+                            "Ignored the reserved code {:?}, it does not fit the format.",
+                            code
+                        );
+                        None
+                    }
+                },
+            )
+            .collect();
+        config
+    }
+}
+
+/// The code written with the characters of the alphabet, letters matching in any case.
+/// None if a character is not in the alphabet.
+pub fn fit_alphabet(code: &str, alphabet: &[char]) -> Option<String> {
+    code.chars()
+        .map(|c| {
+            [c, c.to_ascii_uppercase(), c.to_ascii_lowercase()]
+                .into_iter()
+                .find(|c| alphabet.contains(c))
+        })
+        .collect()
+}
+
+fn env_or<T: FromStr>(key: &str, default: T) -> T {
+    env::var(key)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+// Comma separated values, i.e. `a,b,c`
+fn env_list(key: &str, default: Vec<String>) -> Vec<String> {
+    match env::var(key) {
+        Ok(val) => val
+            .split(',')
+            .map(|item| item.trim().to_owned())
+            .filter(|item| !item.is_empty())
+            .collect(),
+        Err(_) => default,
+    }
+}
+
+// Only letters and digits fit in a path, at least two to pick from
+fn env_alphabet(key: &str, default: Vec<char>) -> Vec<char> {
+    parse_alphabet(&env_or(key, String::new()), default)
+}
+
+fn parse_alphabet(val: &str, default: Vec<char>) -> Vec<char> {
+    let mut alphabet: Vec<char> = val.chars().filter(char::is_ascii_alphanumeric).collect();
+    alphabet.sort_unstable();
+    alphabet.dedup();
+    if alphabet.len() < 2 {
+        return default;
+    }
+    alphabet
+}
+
+fn env_secs(key: &str, default: Duration) -> Duration {
+    Duration::from_secs(env_or(key, default.as_secs()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn alphabet_from_env() {
+        let default: Vec<char> = ('A'..='Z').collect();
+        assert_eq!(
+            parse_alphabet("CBAAB", default.clone()),
+            vec!['A', 'B', 'C']
+        );
+        assert_eq!(
+            parse_alphabet("a1-B 2/", default.clone()),
+            vec!['1', '2', 'B', 'a']
+        );
+        // Not enough to pick from
+        assert_eq!(parse_alphabet("", default.clone()), default);
+        assert_eq!(parse_alphabet("AAA", default.clone()), default);
+        assert_eq!(parse_alphabet("é-ß", default.clone()), default);
+    }
+
+    #[test]
+    fn codes_fit_alphabet() {
+        let upper: Vec<char> = ('A'..='Z').collect();
+        assert_eq!(fit_alphabet("pizz", &upper).as_deref(), Some("PIZZ"));
+        assert_eq!(fit_alphabet("PiZz", &upper).as_deref(), Some("PIZZ"));
+        assert_eq!(fit_alphabet("P1ZZ", &upper), None);
+        assert_eq!(fit_alphabet("", &upper).as_deref(), Some(""));
+
+        let mixed = vec!['a', 'B', '2'];
+        assert_eq!(fit_alphabet("Ab2", &mixed).as_deref(), Some("aB2"));
+        assert_eq!(fit_alphabet("c", &mixed), None);
+    }
+}
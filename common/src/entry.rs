@@ -1,18 +1,24 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::convert::Infallible;
-use std::net::SocketAddr;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 
-use tokio::sync::mpsc::UnboundedSender;
 use tokio_tungstenite::tungstenite::handshake::derive_accept_key;
-use tokio_tungstenite::tungstenite::protocol::Role;
+use tokio_tungstenite::tungstenite::protocol::{Role, WebSocketConfig};
 use tokio_tungstenite::WebSocketStream;
 
+use log::info;
+use tokio::sync::mpsc::error::SendError;
+
 use once_cell::sync::Lazy;
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
 
-use crate::comm::{HostComm, Player};
-use crate::game::{client_handler, game_handler, GameConfig, GameList};
+use crate::comm::{Command, HostComm, JoinParams, Player, PlayerRole, Protocol};
+use crate::config::CONFIG;
+use crate::error::Error;
+use crate::game::{client_handler, game_handler, is_room_code, same_secret, GameConfig, GameList};
+use crate::limit::{address_key, ConnGuard, TokenBucket};
 
 #[cfg(feature = "tls")]
 use hyper::server::conn::AddrIncoming;
@@ -21,8 +27,9 @@ use tls::get_tls_cfg;
 
 use hyper::{
     header::{
-        HeaderValue, CONNECTION, SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_VERSION,
-        UPGRADE,
+        HeaderMap, HeaderValue, ACCESS_CONTROL_ALLOW_ORIGIN, CONNECTION, ORIGIN,
+        SEC_WEBSOCKET_ACCEPT, SEC_WEBSOCKET_KEY, SEC_WEBSOCKET_PROTOCOL, SEC_WEBSOCKET_VERSION,
+        UPGRADE, VARY,
     },
     server::conn::AddrStream,
     service::{make_service_fn, service_fn},
@@ -30,15 +37,93 @@ use hyper::{
     Body, Method, Request, Response, Server, StatusCode, Version,
 };
 
+// Limits on the player metadata given in the query string
+const MAX_META_ENTRIES: usize = 8;
+const MAX_META_KEY_LEN: usize = 32;
+const MAX_META_VALUE_LEN: usize = 128;
+
 static LAST_CLIENT_ID: Lazy<RwLock<u32>> = Lazy::new(|| RwLock::new(0));
 
 static GAME_LIST: Lazy<GameList> =
     Lazy::new(|| Arc::new(RwLock::new(HashMap::<String, GameConfig>::default())));
 
+// Room creations per client address
+static CREATIONS: Lazy<Mutex<HashMap<IpAddr, TokenBucket>>> = Lazy::new(Default::default);
+
+// Whether the client address may create one more room
+fn may_create(ip: IpAddr) -> bool {
+    if CONFIG.max_rooms != 0 && GAME_LIST.read().len() >= CONFIG.max_rooms {
+        return false;
+    }
+    let mut creations = CREATIONS.lock();
+    // Forget the addresses which did not create rooms lately
+    if creations.len() > 1024 {
+        creations.retain(|_, bucket| !bucket.is_full());
+    }
+    let bucket = creations
+        .entry(ip)
+        .or_insert_with(|| TokenBucket::per(CONFIG.max_creates_per_ip, Duration::from_secs(60)));
+    if !bucket.wait(1.0).is_zero() {
+        return false;
+    }
+    bucket.take(1.0);
+    true
+}
+
+// The address of the client, as forwarded by trusted proxies
+fn client_ip(remote: SocketAddr, headers: &HeaderMap, trusted: &[IpAddr]) -> IpAddr {
+    let mut ip = remote.ip();
+    if !trusted.contains(&ip) {
+        return ip;
+    }
+    let forwarded: Vec<IpAddr> = headers
+        .get_all("X-Forwarded-For")
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .filter_map(|addr| addr.trim().parse().ok())
+        .collect();
+    // Each proxy appends the address it got the request from, the client is the last untrusted one
+    for addr in forwarded.into_iter().rev() {
+        ip = addr;
+        if !trusted.contains(&ip) {
+            break;
+        }
+    }
+    ip
+}
+
+// Browsers always send their origin, other clients are not restricted
+fn origin_allowed(origin: Option<&HeaderValue>) -> bool {
+    if CONFIG.allowed_origins.is_empty() {
+        return true;
+    }
+    match origin.map(|origin| origin.to_str()) {
+        Some(Ok(origin)) => {
+            let origin = origin.trim_end_matches('/').to_ascii_lowercase();
+            CONFIG.allowed_origins.contains(&origin)
+        }
+        Some(Err(_)) => false,
+        None => true,
+    }
+}
+
+// Lets the allowed origins read our responses
+fn allow_origin(res: &mut Response<Body>, origin: Option<HeaderValue>) {
+    let headers = res.headers_mut();
+    if CONFIG.allowed_origins.is_empty() {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, HeaderValue::from_static("*"));
+    } else if let Some(origin) = origin {
+        headers.insert(ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        headers.append(VARY, HeaderValue::from_static("Origin"));
+    }
+}
+
 #[derive(Debug, PartialEq)]
 enum ClientConfig {
-    Connect(String),
+    Connect(String, JoinParams),
     Create,
+    Resume(String, String), // (room, host secret)
     Invalid,
 }
 
@@ -47,25 +132,212 @@ async fn handle_connection(
     ws_stream: WebSocketStream<Upgraded>,
     client_id: u32,
     config: ClientConfig,
+    protocol: Option<Protocol>,
+    guard: Option<ConnGuard>,
 ) {
     match config {
-        ClientConfig::Connect(id) => {
-            // If ID exists: Join to the game
-            let to_game: Arc<UnboundedSender<HostComm>> =
-                { GAME_LIST.read().get(&id).unwrap().to_game.clone() };
-            tokio::spawn(client_handler(to_game, Player::new(client_id, ws_stream)));
+        ClientConfig::Connect(id, params) => {
+            // The room may have finished since the HTTP check
+            let room = {
+                GAME_LIST
+                    .read()
+                    .get(&id)
+                    .map(|game| (game.to_game.clone(), game.limit.clone()))
+            };
+            match room {
+                Some((to_game, limit)) => {
+                    let player = Player::new(client_id, ws_stream);
+                    tokio::spawn(client_handler(to_game, limit, player, params));
+                }
+                None => refuse(ws_stream, Error::RoomNotFound(id)).await,
+            }
         }
         ClientConfig::Create => {
-            tokio::spawn(game_handler(ws_stream, GAME_LIST.clone()));
+            tokio::spawn(game_handler(ws_stream, GAME_LIST.clone(), protocol, guard));
+        }
+        ClientConfig::Resume(id, secret) => {
+            let to_game = { GAME_LIST.read().get(&id).map(|game| game.to_game.clone()) };
+            if let Some(to_game) = to_game {
+                // The game checks the secret and takes over the websocket
+                let resume = HostComm::Resume(Box::new(ws_stream), secret, protocol, guard);
+                if let Err(SendError(HostComm::Resume(ws, ..))) = to_game.send(resume).await {
+                    refuse(*ws, Error::RoomClosed).await;
+                }
+            } else {
+                refuse(ws_stream, Error::RoomNotFound(id)).await;
+            }
+        }
+        ClientConfig::Invalid => refuse(ws_stream, Error::InvalidRequest).await,
+    }
+}
+
+// Closes a websocket we cannot serve, telling the client why
+async fn refuse(mut ws: WebSocketStream<Upgraded>, reason: Error) {
+    if let Err(e) = ws.close(Some(reason.close_frame())).await {
+        info!("Failed to refuse a client ({}): {}", reason, Error::from(e));
+    }
+}
+
+fn parse_join_params(query: Option<&str>) -> Result<JoinParams, String> {
+    let mut params = JoinParams::default();
+    for (key, val) in form_urlencoded::parse(query.unwrap_or("").as_bytes()) {
+        if key == "resume" {
+            params.resume = Some(val.into_owned());
+            continue;
+        }
+        if key == "password" {
+            params.password = Some(val.into_owned());
+            continue;
+        }
+        if key == "role" {
+            params.role = match val.as_ref() {
+                "player" => PlayerRole::Player,
+                "audience" => PlayerRole::Audience,
+                _ => return Err("Invalid role, use player or audience.".to_owned()),
+            };
+            continue;
+        }
+        // Anything else is player metadata for the host
+        if key.is_empty()
+            || key.len() > MAX_META_KEY_LEN
+            || !key.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+        {
+            return Err(format!(
+                "Invalid parameter {:?}, use up to {} letters, digits or _.",
+                key, MAX_META_KEY_LEN
+            ));
         }
-        ClientConfig::Invalid => {
-            panic!("We tried to start a connection for an invalid client.")
+        if val.len() > MAX_META_VALUE_LEN || val.chars().any(char::is_control) {
+            return Err(format!(
+                "Invalid value for {:?}, use up to {} bytes without control characters.",
+                key, MAX_META_VALUE_LEN
+            ));
         }
+        params.meta.insert(key.into_owned(), val.into_owned());
+        if params.meta.len() > MAX_META_ENTRIES {
+            return Err(format!(
+                "Too many parameters, at most {} are allowed.",
+                MAX_META_ENTRIES
+            ));
+        }
+    }
+    Ok(params)
+}
+
+// Checks the password of the room, failures are reported to the host and rate limited
+// per client address, so that guessing from one address does not lock everyone out
+fn check_password(key: &str, given: Option<&str>, ip: IpAddr) -> Result<(), (u16, &'static str)> {
+    let mut games = GAME_LIST.write();
+    let game = match games.get_mut(key) {
+        Some(game) => game,
+        // Refused with a close frame once upgraded
+        None => return Ok(()),
+    };
+    let expected = match game.password.as_deref() {
+        Some(expected) => expected,
+        None => return Ok(()),
+    };
+    game.failed_joins.retain(|_, failed| {
+        while let Some(at) = failed.front() {
+            if at.elapsed() < CONFIG.join_attempts_window {
+                break;
+            }
+            failed.pop_front();
+        }
+        !failed.is_empty()
+    });
+    let failed = game.failed_joins.get(&ip).map_or(0, VecDeque::len);
+    if CONFIG.join_attempts != 0 && failed >= CONFIG.join_attempts {
+        return Err((429, "Too many wrong passwords, try again later."));
     }
+    if given.is_some_and(|given| same_secret(given, expected)) {
+        return Ok(());
+    }
+    let failed = game.failed_joins.entry(ip).or_default();
+    failed.push_back(Instant::now());
+    let attempts = failed.len() as u32;
+    let _ = game
+        .to_game
+        .try_send(HostComm::Command(Command::PasswordFailed { attempts }));
+    Err((403, "Wrong password."))
+}
+
+fn plain_response(status: u16, body: impl Into<Body>) -> Result<Response<Body>, Error> {
+    Ok(Response::builder()
+        .status(status)
+        .header("Content-Type", "text/plain")
+        .body(body.into())?)
+}
+
+fn parse_secret(query: Option<&str>) -> String {
+    form_urlencoded::parse(query.unwrap_or("").as_bytes())
+        .find(|(key, _)| key == "secret")
+        .map(|(_, val)| val.into_owned())
+        .unwrap_or_default()
+}
+
+// Hosts may send large states, players only send inputs
+fn ws_config(config: &ClientConfig) -> WebSocketConfig {
+    let (max_message, max_frame) = match config {
+        ClientConfig::Connect(..) => (CONFIG.player_max_message, CONFIG.player_max_frame),
+        _ => (CONFIG.host_max_message, CONFIG.host_max_frame),
+    };
+    WebSocketConfig {
+        max_message_size: Some(max_message),
+        max_frame_size: Some(max_frame),
+        ..WebSocketConfig::default()
+    }
+}
+
+// Picks the first protocol offered by the client which we support, with the name
+// it was offered as since browsers expect that very name in the reply.
+// No offer at all is fine (legacy clients), only unsupported offers are an error.
+fn negotiate_protocol(headers: &HeaderMap) -> Result<Option<(Protocol, String)>, ()> {
+    let offers: Vec<&str> = headers
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|h| h.to_str().ok())
+        .flat_map(|h| h.split(','))
+        .map(|p| p.trim())
+        .filter(|p| !p.is_empty())
+        .collect();
+    if offers.is_empty() {
+        return Ok(None);
+    }
+    offers
+        .into_iter()
+        .find_map(|name| Protocol::parse(name).map(|p| (p, name.to_owned())))
+        .map(Some)
+        .ok_or(())
 }
 
 // Either reply in HTTP or upgrade to websocket
-async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, Infallible> {
+async fn handle_request(
+    req: Request<Body>,
+    remote: SocketAddr,
+) -> Result<Response<Body>, Infallible> {
+    let origin = req.headers().get(ORIGIN).cloned();
+    let mut res = match route(req, remote).await {
+        Ok(res) => res,
+        Err(e) => {
+            info!("Failed to answer a request: {}", e);
+            let mut res = Response::new(Body::from("Internal error"));
+            *res.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            res
+        }
+    };
+    if origin_allowed(origin.as_ref()) {
+        allow_origin(&mut res, origin);
+    }
+    Ok(res)
+}
+
+async fn route(mut req: Request<Body>, remote: SocketAddr) -> Result<Response<Body>, Error> {
+    // Only our own pages may create rooms or embed joins
+    if !origin_allowed(req.headers().get(ORIGIN)) {
+        return plain_response(403, "Origin not allowed.");
+    }
+
     let new_client_id: u32 = {
         let mut id = LAST_CLIENT_ID.write();
         *id += 1;
@@ -75,12 +347,17 @@ async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, Infall
     let config: ClientConfig; // = Arc::new(ClientConfig::Connect("".to_owned()));
     let res: Vec<&str> = req.uri().path().split('/').collect();
 
-    if res.len() != 2 {
+    if res.len() == 3 && res[1] == "RESUME" && is_room_code(res[2]) {
+        config = ClientConfig::Resume(res[2].to_owned(), parse_secret(req.uri().query()));
+    } else if res.len() != 2 {
         config = ClientConfig::Invalid;
-    } else if res[1].len() == 4 {
-        config = ClientConfig::Connect(res[1].to_owned());
     } else if res[1] == "CREATE" {
         config = ClientConfig::Create;
+    } else if is_room_code(res[1]) {
+        match parse_join_params(req.uri().query()) {
+            Ok(params) => config = ClientConfig::Connect(res[1].to_owned(), params),
+            Err(reason) => return plain_response(400, reason),
+        }
     } else {
         config = ClientConfig::Invalid;
     }
@@ -97,8 +374,8 @@ async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, Infall
             .get(CONNECTION)
             .and_then(|h| h.to_str().ok())
             .map(|h| {
-                h.split(|c| c == ' ' || c == ',')
-                    .any(|p| p.eq_ignore_ascii_case(upgrade.to_str().unwrap()))
+                h.split([' ', ','])
+                    .any(|p| p.eq_ignore_ascii_case("Upgrade"))
             })
             .unwrap_or(false)
         || !headers
@@ -114,55 +391,82 @@ async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, Infall
     {
         // Handle the request if we don't want to level up to Websocket mode.
         match &config {
-            ClientConfig::Connect(str) => {
-                if let Some(val) = GAME_LIST.read().get(str) {
-                    return Ok(Response::builder()
-                        .status(200)
-                        .header("Content-Type", "text/plain")
-                        .header("Access-Control-Allow-Origin", "*")
-                        .body(Body::from(val.name.clone()))
-                        .unwrap());
+            ClientConfig::Connect(str, _) => {
+                let name = GAME_LIST.read().get(str).map(|val| val.name.clone());
+                if let Some(name) = name {
+                    return plain_response(200, name);
                 } else {
-                    return Ok(Response::builder()
-                        .status(404)
-                        .header("Content-Type", "text/plain")
-                        .header("Access-Control-Allow-Origin", "*")
-                        .body(Body::from("Game not found"))
-                        .unwrap());
+                    return plain_response(404, "Game not found");
                 }
             }
             ClientConfig::Invalid => {
-                return Ok(Response::builder()
-                    .status(400)
-                    .header("Content-Type", "text/plain")
-                    .header("Access-Control-Allow-Origin", "*")
-                    .body(Body::from(
-                        "Either connect to a room or create one by connecting with a Websocket here.
+                return plain_response(
+                    400,
+                    "Either connect to a room or create one by connecting with a Websocket here.
 
     GET to /ROOM will fetch information about the room
     Connect to /ROOM will try to connect to the room
-    Connect to /CREATE will create a room",
-                    ))
-                    .unwrap());
+    Connect to /CREATE will create a room
+    Connect to /RESUME/ROOM?secret=SECRET will take the room back as its host
+
+    Websocket protocols (Sec-WebSocket-Protocol) are optional, wmbp.v2 or
+    wmbp.v2.cbor, wmbp.v2.json, wmbp.v2.msgpack to pick the host encoding.",
+                );
             }
             _ => {
                 return Ok(Response::new(Body::from("Provide a valid token.")));
             }
         }
     }
+    let (protocol, offered) = match negotiate_protocol(req.headers()) {
+        Ok(Some((protocol, offered))) => (Some(protocol), Some(offered)),
+        Ok(None) => (None, None),
+        Err(_) => {
+            let supported = Protocol::supported().join(", ");
+            let mut res = plain_response(
+                400,
+                format!("Unsupported websocket protocol, use one of: {}", supported),
+            )?;
+            res.headers_mut()
+                .append(SEC_WEBSOCKET_PROTOCOL, supported.parse()?);
+            return Ok(res);
+        }
+    };
+    // Limits last, not to count requests refused anyway
+    let ip = address_key(client_ip(remote, req.headers(), &CONFIG.trusted_proxies));
+    // Only hosts are counted, a classroom full of players may share one address
+    let guard = match config {
+        ClientConfig::Connect(..) => None,
+        _ => match ConnGuard::new(ip) {
+            Some(guard) => Some(guard),
+            None => return plain_response(429, "Too many connections from your address."),
+        },
+    };
+    if config == ClientConfig::Create && !may_create(ip) {
+        return plain_response(429, "Too many rooms, try again later.");
+    }
+    if let ClientConfig::Connect(key, params) = &config {
+        if let Err((status, reason)) = check_password(key, params.password.as_deref(), ip) {
+            return plain_response(status, reason);
+        }
+    }
     let ver = req.version();
+    let derived = derived.ok_or(Error::InvalidRequest)?;
 
     tokio::task::spawn(async move {
         match hyper::upgrade::on(&mut req).await {
             Ok(upgraded) => {
+                let ws_config = ws_config(&config);
                 handle_connection(
-                    WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await,
+                    WebSocketStream::from_raw_socket(upgraded, Role::Server, Some(ws_config)).await,
                     new_client_id,
                     config,
+                    protocol,
+                    guard,
                 )
                 .await;
             }
-            Err(e) => println!("upgrade error: {}", e),
+            Err(e) => info!("Failed to upgrade a connection: {}", e),
         }
     });
 
@@ -172,9 +476,13 @@ async fn handle_request(mut req: Request<Body>) -> Result<Response<Body>, Infall
     res.headers_mut().append(CONNECTION, upgrade);
     res.headers_mut().append(UPGRADE, websocket);
     res.headers_mut()
-        .append(SEC_WEBSOCKET_ACCEPT, derived.unwrap().parse().unwrap());
+        .append(SEC_WEBSOCKET_ACCEPT, derived.parse()?);
+    if let Some(offered) = offered {
+        res.headers_mut()
+            .append(SEC_WEBSOCKET_PROTOCOL, offered.parse()?);
+    }
     res.headers_mut()
-        .append("ILoveRust", "Yes, I do.".parse().unwrap());
+        .append("ILoveRust", HeaderValue::from_static("Yes, I do."));
     Ok(res)
 }
 
@@ -183,8 +491,9 @@ pub async fn service(addr: SocketAddr) -> Result<(), hyper::Error> {
     let server = {
         let incoming = AddrIncoming::bind(&addr)?;
         let tls_cfg = get_tls_cfg();
-        let make_svc = make_service_fn(move |_conn: &tls::TlsStream| {
-            let service = service_fn(handle_request);
+        let make_svc = make_service_fn(move |conn: &tls::TlsStream| {
+            let remote = conn.remote_addr();
+            let service = service_fn(move |req| handle_request(req, remote));
             async move { Ok::<_, Infallible>(service) }
         });
         Server::builder(tls::TlsAcceptor::new(tls_cfg, incoming)).serve(make_svc)
@@ -192,8 +501,9 @@ pub async fn service(addr: SocketAddr) -> Result<(), hyper::Error> {
 
     #[cfg(not(feature = "tls"))]
     let server = {
-        let make_svc = make_service_fn(move |_: &AddrStream| {
-            let service = service_fn(handle_request);
+        let make_svc = make_service_fn(move |conn: &AddrStream| {
+            let remote = conn.remote_addr();
+            let service = service_fn(move |req| handle_request(req, remote));
             async move { Ok::<_, Infallible>(service) }
         });
         Server::bind(&addr).serve(make_svc)
@@ -203,3 +513,97 @@ pub async fn service(addr: SocketAddr) -> Result<(), hyper::Error> {
 
     Ok::<_, hyper::Error>(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn join_params_reserved() {
+        let params = parse_join_params(Some("resume=abc&role=audience&password=p%20w")).unwrap();
+        assert_eq!(params.resume.as_deref(), Some("abc"));
+        assert_eq!(params.role, PlayerRole::Audience);
+        assert_eq!(params.password.as_deref(), Some("p w"));
+        assert!(params.meta.is_empty());
+
+        let params = parse_join_params(Some("resume=")).unwrap();
+        assert_eq!(params.resume.as_deref(), Some(""));
+        assert_eq!(parse_join_params(None).unwrap(), JoinParams::default());
+        assert!(parse_join_params(Some("role=host")).is_err());
+    }
+
+    #[test]
+    fn join_params_meta() {
+        let params = parse_join_params(Some("name=Ana&avatar_id=3&name=Bo")).unwrap();
+        assert_eq!(params.meta.len(), 2);
+        assert_eq!(params.meta["name"], "Bo");
+        assert_eq!(params.meta["avatar_id"], "3");
+
+        assert!(parse_join_params(Some("=x")).is_err());
+        assert!(parse_join_params(Some("na-me=x")).is_err());
+        assert!(parse_join_params(Some("name=a%0Ab")).is_err());
+        let long_key = "k".repeat(MAX_META_KEY_LEN + 1);
+        assert!(parse_join_params(Some(&format!("{}=x", long_key))).is_err());
+        let long_value = "v".repeat(MAX_META_VALUE_LEN + 1);
+        assert!(parse_join_params(Some(&format!("name={}", long_value))).is_err());
+    }
+
+    #[test]
+    fn join_params_too_many() {
+        let query = |count: usize| {
+            (0..count)
+                .map(|i| format!("k{}=v", i))
+                .collect::<Vec<_>>()
+                .join("&")
+        };
+        assert!(parse_join_params(Some(&query(MAX_META_ENTRIES))).is_ok());
+        assert!(parse_join_params(Some(&query(MAX_META_ENTRIES + 1))).is_err());
+    }
+
+    fn forwarded(values: &[&str]) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        for value in values {
+            headers.append("X-Forwarded-For", value.parse().unwrap());
+        }
+        headers
+    }
+
+    fn ip(addr: &str) -> IpAddr {
+        addr.parse().unwrap()
+    }
+
+    #[test]
+    fn client_ip_untrusted_remote() {
+        let remote = SocketAddr::new(ip("1.1.1.1"), 80);
+        let headers = forwarded(&["2.2.2.2"]);
+        assert_eq!(client_ip(remote, &headers, &[]), ip("1.1.1.1"));
+        assert_eq!(client_ip(remote, &headers, &[ip("3.3.3.3")]), ip("1.1.1.1"));
+    }
+
+    #[test]
+    fn client_ip_through_proxies() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2")];
+        let remote = SocketAddr::new(ip("10.0.0.1"), 80);
+        // The client may forge the first entries, only the ones added by proxies count
+        let headers = forwarded(&["6.6.6.6, 2.2.2.2", "10.0.0.2"]);
+        assert_eq!(client_ip(remote, &headers, &proxies), ip("2.2.2.2"));
+        let headers = forwarded(&["6.6.6.6,nonsense, 2.2.2.2 ,10.0.0.2"]);
+        assert_eq!(client_ip(remote, &headers, &proxies), ip("2.2.2.2"));
+        // Nothing forwarded, the proxy is the client
+        assert_eq!(
+            client_ip(remote, &HeaderMap::new(), &proxies),
+            ip("10.0.0.1")
+        );
+    }
+
+    #[test]
+    fn client_ip_all_trusted() {
+        let proxies = [ip("10.0.0.1"), ip("10.0.0.2"), ip("10.0.0.3")];
+        let remote = SocketAddr::new(ip("10.0.0.1"), 80);
+        // The request started at a proxy, the first one in the chain is the client
+        let headers = forwarded(&["10.0.0.3, 10.0.0.2"]);
+        assert_eq!(client_ip(remote, &headers, &proxies), ip("10.0.0.3"));
+        let headers = forwarded(&["nonsense"]);
+        assert_eq!(client_ip(remote, &headers, &proxies), ip("10.0.0.1"));
+    }
+}
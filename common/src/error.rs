@@ -0,0 +1,94 @@
+use std::borrow::Cow;
+use std::fmt;
+
+use hyper::header::InvalidHeaderValue;
+use tokio_tungstenite::tungstenite;
+use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
+use tokio_tungstenite::tungstenite::protocol::CloseFrame;
+
+/// Everything that can go wrong while serving rooms and their players.
+#[derive(Debug)]
+pub enum Error {
+    /// The websocket failed, the other side is gone.
+    Socket(Box<tungstenite::Error>),
+    /// An HTTP response could not be built.
+    Http(hyper::http::Error),
+    /// A command could not be serialized.
+    Encode(String),
+    /// No room with this key, it may have just finished.
+    RoomNotFound(String),
+    /// The room stopped while handling the connection.
+    RoomClosed,
+    /// The room is full or already started.
+    RoomFull,
+    /// The peer sent a message or frame above the configured size.
+    TooLarge,
+    /// The host did not admit the player.
+    Rejected,
+    /// The host secret given to resume a room is wrong.
+    InvalidSecret,
+    /// The request is neither a room, CREATE nor RESUME.
+    InvalidRequest,
+}
+
+impl Error {
+    /// The frame closing a websocket refused because of this error.
+    pub fn close_frame(&self) -> CloseFrame<'static> {
+        let code = match self {
+            Error::Socket(_) | Error::Http(_) | Error::Encode(_) => CloseCode::Error,
+            Error::RoomNotFound(_) | Error::RoomClosed => CloseCode::Away,
+            Error::RoomFull => CloseCode::Again,
+            Error::TooLarge => CloseCode::Size,
+            Error::Rejected | Error::InvalidSecret | Error::InvalidRequest => CloseCode::Policy,
+        };
+        CloseFrame {
+            code,
+            reason: Cow::Owned(self.to_string()),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Socket(e) => write!(f, "Websocket error: {}", e),
+            Error::Http(e) => write!(f, "HTTP error: {}", e),
+            Error::Encode(e) => write!(f, "Failed to encode a command: {}", e),
+            Error::RoomNotFound(key) => write!(f, "Game {} not found.", key),
+            Error::RoomClosed => write!(f, "The game is done."),
+            Error::RoomFull => write!(f, "The game is full or already started."),
+            Error::TooLarge => write!(f, "The message is too large."),
+            Error::Rejected => write!(f, "The host rejected you."),
+            Error::InvalidSecret => write!(f, "Invalid host secret."),
+            Error::InvalidRequest => write!(f, "Invalid request."),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Socket(e) => Some(e.as_ref()),
+            Error::Http(e) => Some(e),
+            _ => None,
+        }
+    }
+}
+
+impl From<tungstenite::Error> for Error {
+    fn from(e: tungstenite::Error) -> Self {
+        Error::Socket(Box::new(e))
+    }
+}
+
+impl From<hyper::http::Error> for Error {
+    fn from(e: hyper::http::Error) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<InvalidHeaderValue> for Error {
+    fn from(e: InvalidHeaderValue) -> Self {
+        Error::Http(e.into())
+    }
+}
@@ -1,233 +1,1213 @@
+use futures::future::pending;
 use futures::{select, FutureExt};
 use futures_util::{SinkExt, StreamExt};
 use hyper::upgrade::Upgraded;
 use log::info;
 
 use std::borrow::Cow;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::net::IpAddr;
 use std::sync::Arc;
-use tokio::sync::mpsc::{unbounded_channel, UnboundedSender};
+use std::time::{Duration, Instant};
+use tokio::sync::mpsc::{channel, error::SendError, Permit, Sender};
+use tokio::sync::oneshot;
 
 use tokio_tungstenite::tungstenite::protocol::frame::coding::CloseCode;
-use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Error, Message, Result};
+use tokio_tungstenite::tungstenite::{protocol::CloseFrame, Error as WsError, Message, Result};
 use tokio_tungstenite::WebSocketStream;
 
-use parking_lot::RwLock;
+use parking_lot::{Mutex, RwLock};
+use serde::Deserialize;
 
-use crate::comm::{Command, HostComm, Player, PlayerSink};
+use crate::comm::{
+    Command, Encoding, ErrorCode, ExpiryReason, HostComm, HostRequest, JoinParams, Player,
+    PlayerInfo, PlayerJoin, PlayerList, PlayerMeta, PlayerRole, PlayerSink, Protocol,
+    HOST_COMMANDS, PROTOCOL_VERSIONS,
+};
+use crate::config::{fit_alphabet, QueuePolicy, RatePolicy, SlowPolicy, CONFIG};
+use crate::error::Error;
+use crate::limit::{ConnGuard, RateLimit};
 
 use ciborium;
 
+const MAX_GROUP_NAME_LEN: usize = 32;
+
 pub struct GameConfig {
-    pub to_game: Arc<UnboundedSender<HostComm>>,
+    pub to_game: Arc<Sender<HostComm>>,
     pub name: String,
+    pub password: Option<String>,
+    // Recent wrong passwords per client address, to rate limit guessing
+    pub failed_joins: HashMap<IpAddr, VecDeque<Instant>>,
+    // Shared by the players of the room
+    pub limit: Arc<Mutex<RateLimit>>,
 }
 
 pub type GameList = Arc<RwLock<HashMap<String, GameConfig>>>;
 
+use rand::distributions::Alphanumeric;
 use rand::thread_rng;
 use rand::Rng;
 
-fn gen_room_code() -> String {
+// A random code in the configured format, None if it contains a blocked word
+fn gen_room_code() -> Option<String> {
     let mut rng = thread_rng();
-    let mut val: String = "".to_owned();
-    for _ in 0..4 {
-        let random_val: u8 = rng.gen_range(65..91);
-        val.push(random_val as char);
-    }
-    val
-}
-
-fn read_command(msg: Option<Result<Message, Error>>) -> Option<Command> {
-    if let Some(Ok(msg)) = msg {
-        match msg {
-            Message::Text(x) => {
-                let val: Result<Command, _> = serde_json::from_str(x.as_str());
-                match val {
-                    Ok(valid_json) => return Some(valid_json),
-                    Err(_) => return None,
-                }
-            }
-            Message::Binary(x) => {
-                let val: Result<Command, _> = ciborium::de::from_reader(x.as_slice());
-                // let val: Result<Command, _> = serde_json::from_str(x.as_str());
-                match val {
-                    Ok(valid_json) => return Some(valid_json),
-                    Err(_) => return None,
+    let alphabet = &CONFIG.room_code_alphabet;
+    let val: String = (0..CONFIG.room_code_length)
+        .map(|_| alphabet[rng.gen_range(0..alphabet.len())])
+        .collect();
+    let upper = val.to_uppercase();
+    if CONFIG
+        .room_code_blocklist
+        .iter()
+        .any(|word| upper.contains(word.as_str()))
+    {
+        return None;
+    }
+    Some(val)
+}
+
+// Reserved codes are kept for the hosts knowing their secret
+fn may_use_code(code: &str, code_secret: Option<&str>) -> bool {
+    match CONFIG.reserved_codes.get(code) {
+        Some(reserved) => code_secret == Some(reserved.as_str()),
+        None => true,
+    }
+}
+
+// Registers the room under the requested code, or a random one when there is none
+fn register_room(
+    game_list: &GameList,
+    code: Option<String>,
+    code_secret: Option<&str>,
+    game: GameConfig,
+) -> std::result::Result<String, ErrorCode> {
+    let mut games = game_list.write();
+    if let Some(code) = code {
+        // Matching the case of the alphabet, the blocklist ignores it already
+        let code = match fit_alphabet(&code, &CONFIG.room_code_alphabet) {
+            Some(code) if is_room_code(&code) => code,
+            _ => return Err(ErrorCode::InvalidRoomCode),
+        };
+        if !may_use_code(&code, code_secret) {
+            return Err(ErrorCode::RoomCodeReserved);
+        }
+        if games.contains_key(&code) {
+            return Err(ErrorCode::RoomCodeTaken);
+        }
+        games.insert(code.clone(), game);
+        return Ok(code);
+    }
+    for _ in 0..CONFIG.room_code_retries {
+        let val = match gen_room_code() {
+            Some(val) if is_room_code(&val) && may_use_code(&val, None) => val,
+            _ => continue,
+        };
+        if !games.contains_key(&val) {
+            games.insert(val.clone(), game);
+            return Ok(val);
+        }
+    }
+    Err(ErrorCode::RoomKeyUnavailable)
+}
+
+/// Whether the path segment can be a room code, CREATE and RESUME never are.
+pub fn is_room_code(code: &str) -> bool {
+    code.chars().count() == CONFIG.room_code_length
+        && code.chars().all(|c| CONFIG.room_code_alphabet.contains(&c))
+        && code != "CREATE"
+        && code != "RESUME"
+}
+
+fn gen_token() -> String {
+    thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(24)
+        .map(char::from)
+        .collect()
+}
+
+/// Compares in constant time, not to leak how much of a password or secret matched.
+pub fn same_secret(given: &str, expected: &str) -> bool {
+    given.len() == expected.len()
+        && given
+            .bytes()
+            .zip(expected.bytes())
+            .fold(0, |diff, (a, b)| diff | (a ^ b))
+            == 0
+}
+
+// Relayed text is `WMBR2,3:payload` like binary, no id sends to every other player.
+// A tag rather than a single character, chat like `>12:30` is not an envelope.
+fn parse_relay_str(data: &str) -> Option<(Vec<u32>, &str)> {
+    let (ids, payload) = data.strip_prefix("WMBR")?.split_once(':')?;
+    let ids = ids
+        .split(',')
+        .filter(|id| !id.is_empty())
+        .map(|id| id.trim().parse().ok())
+        .collect::<Option<Vec<u32>>>()?;
+    Some((ids, payload))
+}
+
+// Relayed binary is `WMBR`, the number of ids (u8), the ids (u32 big endian) and the payload
+fn parse_relay(data: &[u8]) -> Option<(Vec<u32>, &[u8])> {
+    let data = data.strip_prefix(b"WMBR")?;
+    let (count, data) = data.split_first()?;
+    let len = *count as usize * 4;
+    if data.len() < len {
+        return None;
+    }
+    let (ids, payload) = data.split_at(len);
+    let ids = ids
+        .chunks_exact(4)
+        .map(|id| u32::from_be_bytes([id[0], id[1], id[2], id[3]]))
+        .collect();
+    Some((ids, payload))
+}
+
+// Only the tag of a command, to tell why it could not be read
+#[derive(Deserialize)]
+struct Tag {
+    cmd: String,
+    #[serde(default)]
+    req_id: Option<u32>,
+}
+
+// A host message which is not a valid command
+struct Rejected {
+    req_id: Option<u32>,
+    code: ErrorCode,
+    command: Option<String>,
+}
+
+fn decode_error(tag: Option<Tag>) -> Rejected {
+    match tag {
+        Some(tag) => Rejected {
+            code: if HOST_COMMANDS.contains(&tag.cmd.as_str()) {
+                ErrorCode::InvalidCommand
+            } else {
+                ErrorCode::UnknownCommand
+            },
+            req_id: tag.req_id,
+            command: Some(tag.cmd),
+        },
+        None => Rejected {
+            req_id: None,
+            code: ErrorCode::InvalidFrame,
+            command: None,
+        },
+    }
+}
+
+// Text is always JSON, binary is either CBOR or MessagePack
+fn read_command(msg: Message, encoding: Encoding) -> Result<HostRequest, Rejected> {
+    match msg {
+        Message::Text(x) => serde_json::from_str(x.as_str())
+            .map_err(|_| decode_error(serde_json::from_str(x.as_str()).ok())),
+        Message::Binary(x) if encoding == Encoding::Msgpack => {
+            rmp_serde::from_slice(&x).map_err(|_| decode_error(rmp_serde::from_slice(&x).ok()))
+        }
+        Message::Binary(x) => ciborium::de::from_reader(x.as_slice())
+            .map_err(|_| decode_error(ciborium::de::from_reader(x.as_slice()).ok())),
+        Message::Close(_x) => Ok(HostRequest {
+            req_id: None,
+            command: Command::Stop,
+        }),
+        _ => Err(Rejected {
+            req_id: None,
+            code: ErrorCode::InvalidFrame,
+            command: None,
+        }),
+    }
+}
+
+// The `cmd` tag of a command
+fn command_name(command: &Command) -> Option<String> {
+    serde_json::to_value(command)
+        .ok()?
+        .get("cmd")?
+        .as_str()
+        .map(str::to_owned)
+}
+
+fn to_message(command: Command, encoding: Encoding) -> Result<Message, Error> {
+    match encoding {
+        Encoding::Cbor => {
+            let mut buf: Vec<u8> = Vec::new();
+            ciborium::ser::into_writer(&command, &mut buf)
+                .map_err(|e| Error::Encode(e.to_string()))?;
+            Ok(Message::Binary(buf))
+        }
+        Encoding::Json => to_json(command),
+        // Named fields are required for the "cmd" tag
+        Encoding::Msgpack => rmp_serde::to_vec_named(&command)
+            .map(Message::Binary)
+            .map_err(|e| Error::Encode(e.to_string())),
+    }
+}
+
+fn to_json(command: Command) -> Result<Message, Error> {
+    serde_json::to_string(&command)
+        .map(Message::Text)
+        .map_err(|e| Error::Encode(e.to_string()))
+}
+
+async fn on_lagging(host: &mut Host, room: &mut Room, lagging: Vec<u32>) {
+    for player in lagging {
+        match CONFIG.slow_policy {
+            SlowPolicy::Skip => {}
+            SlowPolicy::Notify => host.send(Command::Lagging { player }).await,
+            SlowPolicy::Disconnect => {
+                info!("Player {} reads too slowly, disconnecting.", player);
+                if let Some(conn) = room.sink_mut(player) {
+                    conn.kill();
                 }
             }
-            Message::Close(_x) => {
-                return Some(Command::Stop);
+        }
+    }
+}
+
+enum Beat {
+    Ping(Vec<u8>),
+    Dead,
+}
+
+/// Pings a peer and tells once it stopped answering, which half-open sockets never do.
+struct Heartbeat {
+    interval: Duration,
+    timeout: Duration,
+    next_ping: Instant,
+    seq: u64,
+    // Sequence number and time of the unanswered ping
+    pending: Option<(u64, Instant)>,
+}
+
+impl Heartbeat {
+    fn new(interval: Duration, timeout: Duration) -> Self {
+        Self {
+            interval,
+            timeout,
+            next_ping: Instant::now() + interval,
+            seq: 0,
+            pending: None,
+        }
+    }
+
+    // Resolves when a ping is due or the last one is unanswered for too long
+    async fn tick(&mut self) -> Beat {
+        if self.interval.is_zero() {
+            return pending().await;
+        }
+        if let Some((_, sent)) = self.pending {
+            tokio::time::sleep_until((sent + self.timeout).into()).await;
+            return Beat::Dead;
+        }
+        tokio::time::sleep_until(self.next_ping.into()).await;
+        let now = Instant::now();
+        self.seq += 1;
+        self.pending = Some((self.seq, now));
+        self.next_ping = now + self.interval;
+        Beat::Ping(self.seq.to_be_bytes().to_vec())
+    }
+
+    // The ping could not be sent, the next one is due after the interval anyway
+    fn skip(&mut self) {
+        self.pending = None;
+    }
+
+    // The round trip time if the pong answers the pending ping
+    fn pong(&mut self, payload: &[u8]) -> Option<Duration> {
+        match self.pending {
+            Some((seq, sent)) if payload == seq.to_be_bytes() => {
+                self.pending = None;
+                Some(sent.elapsed())
             }
-            _ => return None,
+            _ => None,
+        }
+    }
+}
+
+enum Expiry {
+    Warn(ExpiryReason, Duration),
+    Close(ExpiryReason),
+}
+
+/// Closes abandoned rooms, and rooms living for too long anyway.
+struct Lifetime {
+    created: Instant,
+    active: Instant,
+    // The deadline the host was warned about, warned again if it moves
+    warned: Option<Instant>,
+}
+
+impl Lifetime {
+    fn new() -> Self {
+        let now = Instant::now();
+        Self {
+            created: now,
+            active: now,
+            warned: None,
+        }
+    }
+
+    // Some traffic from the host or a player
+    fn touch(&mut self) {
+        self.active = Instant::now();
+    }
+
+    fn check(&mut self) -> Option<Expiry> {
+        let idle = (!CONFIG.room_idle.is_zero())
+            .then(|| (self.active + CONFIG.room_idle, ExpiryReason::Idle));
+        let lifetime = (!CONFIG.room_lifetime.is_zero())
+            .then(|| (self.created + CONFIG.room_lifetime, ExpiryReason::Lifetime));
+        let (deadline, reason) = idle.into_iter().chain(lifetime).min_by_key(|(at, _)| *at)?;
+        let left = deadline.saturating_duration_since(Instant::now());
+        if left.is_zero() {
+            Some(Expiry::Close(reason))
+        } else if left <= CONFIG.room_warning && self.warned != Some(deadline) {
+            self.warned = Some(deadline);
+            Some(Expiry::Warn(reason, left))
+        } else {
+            None
         }
     }
-    None
 }
 
-fn to_message(command: Command) -> Message {
-    let mut buf: Vec<u8> = Vec::new();
-    let res = ciborium::ser::into_writer(&command, &mut buf);
-    if res.is_ok() {}
-    Message::Binary(buf)
+// A new player waiting for the host to admit it
+struct Pending {
+    sink: PlayerSink,
+    // Asked for a resumable session
+    session: bool,
+    admitted: oneshot::Sender<u32>,
 }
 
-#[allow(dead_code)]
-fn to_json(command: Command) -> Message {
-    let val = serde_json::to_string(&command).unwrap();
-    Message::Text(val)
+// A resumable player without connection, keeping its seat
+struct Away {
+    since: Instant,
+    meta: PlayerMeta,
 }
 
-fn to_state(
-    name: &str,
-    connections: &HashMap<u32, PlayerSink>,
+// Group names are short words, i.e. "red" or "team_1"
+fn is_group_name(group: &str) -> bool {
+    !group.is_empty()
+        && group.len() <= MAX_GROUP_NAME_LEN
+        && group
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+// Addressing of To and ToStr, without the room to test it
+fn pick_recipients<'a>(
+    players: impl Iterator<Item = &'a u32>,
+    to: Vec<u32>,
+    members: Option<&HashSet<u32>>,
+    spectators: Option<impl Iterator<Item = &'a u32>>,
+    except: &[u32],
+) -> Vec<u32> {
+    let mut dest: Vec<u32> = if to.is_empty() && members.is_none() && spectators.is_none() {
+        players.cloned().collect()
+    } else {
+        to
+    };
+    if let Some(members) = members {
+        dest.extend(members);
+    }
+    if let Some(spectators) = spectators {
+        dest.extend(spectators);
+    }
+    dest.retain(|p| !except.contains(p));
+    dest.sort_unstable();
+    dest.dedup();
+    dest
+}
+
+// Everything about the players of a game
+#[derive(Default)]
+struct Room {
+    name: String,
     max_players: u32,
-    accept_conns: bool,
-) -> Command {
-    let players: Vec<u32> = connections.keys().cloned().collect();
-    let accept_connections = accept_conns && players.len() < max_players.try_into().unwrap();
-    Command::State {
-        name: name.to_string(),
-        players,
-        max_players,
-        accept_conns: accept_connections,
+    max_spectators: u32,
+    accept_players: bool,
+    // Admission may open again after Start, i.e. between rounds
+    started: bool,
+    connections: HashMap<u32, PlayerSink>,
+    // Watching only, never counted as players
+    spectators: HashMap<u32, PlayerSink>,
+    // Resume token to player id, and the resumable players which are gone
+    sessions: HashMap<String, u32>,
+    away: HashMap<u32, Away>,
+    groups: HashMap<String, HashSet<u32>>,
+    // Whether players may relay to other players (None if not) and if the host
+    // wants a copy, per player on top of the rule for everyone
+    relay: HashMap<u32, Option<bool>>,
+    relay_all: Option<bool>,
+    // New players wait in `pending` for the host to admit them
+    approval: bool,
+    pending: HashMap<u32, Pending>,
+}
+
+impl Room {
+    // Disconnected players keep their seat until their grace period ends,
+    // waiting players hold one until the host decides
+    fn seats(&self) -> usize {
+        self.connections.len() + self.away.len() + self.pending.len()
+    }
+
+    fn has_seat(&self) -> bool {
+        self.accept_players && self.seats() < self.max_players as usize
+    }
+
+    // Seats a new player, with a resumable session if it asked for one
+    fn seat(
+        &mut self,
+        sink: PlayerSink,
+        session: bool,
+        admitted: oneshot::Sender<u32>,
+    ) -> (u32, PlayerMeta) {
+        let player = sink.id;
+        if session {
+            let token = gen_token();
+            self.sessions.insert(token.clone(), player);
+            if let Ok(msg) = to_json(Command::Session { player, token }) {
+                sink.send(msg);
+            }
+        }
+        let _ = admitted.send(player);
+        let meta = sink.meta.clone();
+        self.connections.insert(player, sink);
+        (player, meta)
+    }
+
+    fn state(&self, version: u32) -> Command {
+        let players = if version >= 2 {
+            PlayerList::Info(
+                self.connections
+                    .values()
+                    .map(|conn| PlayerInfo {
+                        id: conn.id,
+                        meta: conn.meta.clone(),
+                    })
+                    .collect(),
+            )
+        } else {
+            PlayerList::Ids(self.connections.keys().cloned().collect())
+        };
+        let accept_connections = self.has_seat();
+        Command::State {
+            name: self.name.clone(),
+            players,
+            max_players: self.max_players,
+            accept_conns: accept_connections,
+            spectators: self.spectators.keys().cloned().collect(),
+            max_spectators: self.max_spectators,
+            groups: self
+                .groups
+                .iter()
+                .map(|(group, members)| (group.clone(), members.iter().cloned().collect()))
+                .collect(),
+            pending: self.pending.keys().cloned().collect(),
+        }
+    }
+
+    // Who a To or ToStr goes to, see `Command::To`
+    fn recipients(
+        &self,
+        to: Vec<u32>,
+        group: Option<String>,
+        audience: bool,
+        except: &[u32],
+    ) -> std::result::Result<Vec<u32>, ErrorCode> {
+        let members = match group {
+            Some(group) => Some(self.groups.get(&group).ok_or(ErrorCode::GroupNotFound)?),
+            None => None,
+        };
+        let spectators = audience.then(|| self.spectators.keys());
+        Ok(pick_recipients(
+            self.connections.keys(),
+            to,
+            members,
+            spectators,
+            except,
+        ))
+    }
+
+    // Players or spectators with a live connection
+    fn is_connected(&self, id: u32) -> bool {
+        self.connections.contains_key(&id) || self.spectators.contains_key(&id)
+    }
+
+    // Players of any kind, including the ones which may resume
+    fn is_known(&self, id: u32) -> bool {
+        self.connections.contains_key(&id)
+            || self.spectators.contains_key(&id)
+            || self.away.contains_key(&id)
+    }
+
+    // The player is gone for good
+    fn forget(&mut self, id: u32) {
+        self.away.remove(&id);
+        self.sessions.retain(|_, p| *p != id);
+        for members in self.groups.values_mut() {
+            members.remove(&id);
+        }
+        self.relay.remove(&id);
+    }
+
+    // Whether the player may relay, and if the host wants a copy
+    fn relay_copy(&self, id: u32) -> Option<bool> {
+        if !self.connections.contains_key(&id) {
+            return None;
+        }
+        match self.relay.get(&id) {
+            Some(rule) => *rule,
+            None => self.relay_all,
+        }
+    }
+
+    // Players a relayed frame goes to, an empty `to` is every other player
+    fn relay_targets(&self, from: u32, to: Vec<u32>) -> Vec<u32> {
+        let mut dest: Vec<u32> = if to.is_empty() {
+            self.connections.keys().cloned().collect()
+        } else {
+            to
+        };
+        dest.retain(|p| *p != from && self.connections.contains_key(p));
+        dest.sort_unstable();
+        dest.dedup();
+        dest
+    }
+
+    // Queues the message for each player without waiting for any of them.
+    // Returns the players whose queue was full, and the ones which just started lagging behind.
+    fn deliver(&mut self, dest: &[u32], msg: &Message) -> (Vec<u32>, Vec<u32>) {
+        let mut dropped = vec![];
+        let mut lagging = vec![];
+        for player in dest.iter() {
+            if let Some(conn) = self.sink_mut(*player) {
+                if conn.send(msg.clone()) {
+                    conn.lagging = false;
+                    continue;
+                }
+                dropped.push(*player);
+                if !conn.lagging {
+                    conn.lagging = true;
+                    lagging.push(*player);
+                }
+            }
+        }
+        (dropped, lagging)
+    }
+
+    fn sink_mut(&mut self, id: u32) -> Option<&mut PlayerSink> {
+        self.connections
+            .get_mut(&id)
+            .or_else(|| self.spectators.get_mut(&id))
+    }
+}
+
+/// The game's websocket, which may go away for a while once the room exists.
+/// Commands sent meanwhile are buffered until the host resumes.
+struct Host {
+    ws: Option<WebSocketStream<Upgraded>>,
+    // Branch on this when the commands of a version change
+    version: u32,
+    encoding: Encoding,
+    away_since: Option<Instant>,
+    buffer: VecDeque<Command>,
+    heartbeat: Heartbeat,
+    // Counts the websocket against the address of the host while it is open
+    guard: Option<ConnGuard>,
+}
+
+impl Host {
+    fn new(
+        ws: WebSocketStream<Upgraded>,
+        protocol: Option<Protocol>,
+        guard: Option<ConnGuard>,
+    ) -> Self {
+        // Legacy hosts without a subprotocol speak the first version
+        let version = protocol.map_or(PROTOCOL_VERSIONS[0], |p| p.version);
+        Self {
+            ws: Some(ws),
+            version,
+            encoding: protocol.and_then(|p| p.encoding).unwrap_or_default(),
+            away_since: None,
+            buffer: VecDeque::new(),
+            heartbeat: Heartbeat::new(CONFIG.host_ping_interval, CONFIG.host_pong_timeout),
+            guard,
+        }
+    }
+
+    async fn send(&mut self, command: Command) {
+        if let Some(ws) = self.ws.as_mut() {
+            let msg = match to_message(command.clone(), self.encoding) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    info!("Dropped a command for the host: {}", e);
+                    return;
+                }
+            };
+            match ws.send(msg).await {
+                Ok(()) => return,
+                Err(e) => info!("The host failed: {}", e),
+            }
+            self.lost();
+        }
+        if self.buffer.len() >= CONFIG.host_buffer {
+            self.buffer.pop_front();
+        }
+        self.buffer.push_back(command);
+    }
+
+    // Never resolves while the host is away
+    // Pings the host meanwhile, a host which stopped answering is like a closed one
+    async fn next(&mut self) -> Option<Result<Message>> {
+        let ws = match self.ws.as_mut() {
+            Some(ws) => ws,
+            None => return pending().await,
+        };
+        loop {
+            select! {
+                msg = ws.next().fuse() => match msg {
+                    Some(Ok(Message::Pong(data))) => {
+                        self.heartbeat.pong(&data);
+                    }
+                    // Answered by tungstenite
+                    Some(Ok(Message::Ping(_))) => {}
+                    msg => return msg,
+                },
+                beat = self.heartbeat.tick().fuse() => match beat {
+                    Beat::Ping(payload) => {
+                        if ws.send(Message::Ping(payload)).await.is_err() {
+                            return None;
+                        }
+                    }
+                    Beat::Dead => {
+                        info!("The host stopped answering pings.");
+                        return None;
+                    }
+                },
+            }
+        }
+    }
+
+    fn lost(&mut self) {
+        self.ws = None;
+        self.guard = None;
+        if self.away_since.is_none() {
+            self.away_since = Some(Instant::now());
+        }
+    }
+
+    async fn resume(
+        &mut self,
+        ws: WebSocketStream<Upgraded>,
+        protocol: Option<Protocol>,
+        guard: Option<ConnGuard>,
+    ) {
+        // The previous connection may still look alive (half-open)
+        if let Some(mut old) = self.ws.replace(ws) {
+            let _ = old.close(None).await;
+        }
+        self.away_since = None;
+        self.guard = guard;
+        self.heartbeat = Heartbeat::new(CONFIG.host_ping_interval, CONFIG.host_pong_timeout);
+        if let Some(protocol) = protocol {
+            self.version = protocol.version;
+            if let Some(encoding) = protocol.encoding {
+                self.encoding = encoding;
+            }
+        }
+    }
+
+    async fn flush(&mut self) {
+        while let Some(command) = self.buffer.pop_front() {
+            let msg = match to_message(command.clone(), self.encoding) {
+                Ok(msg) => msg,
+                Err(e) => {
+                    info!("Dropped a command for the host: {}", e);
+                    continue;
+                }
+            };
+            let sent = match self.ws.as_mut() {
+                Some(ws) => ws.send(msg).await.is_ok(),
+                None => false,
+            };
+            if !sent {
+                self.buffer.push_front(command);
+                self.lost();
+                break;
+            }
+        }
+    }
+
+    // Replies with a Nack to requests, with an Error otherwise
+    async fn fail(&mut self, req_id: Option<u32>, code: ErrorCode, command: Option<&str>) {
+        let reply = match req_id {
+            Some(req_id) => Command::Nack {
+                req_id,
+                code,
+                reason: code.reason().to_owned(),
+            },
+            None => Command::error(code, command),
+        };
+        self.send(reply).await;
+    }
+
+    // Only requests are acknowledged
+    async fn ack(&mut self, req_id: Option<u32>, missing: Vec<u32>, dropped: Vec<u32>) {
+        if let Some(req_id) = req_id {
+            self.send(Command::Ack {
+                req_id,
+                missing,
+                dropped,
+            })
+            .await;
+        }
+    }
+
+    async fn close(&mut self, msg: Option<CloseFrame<'static>>) {
+        if let Some(mut ws) = self.ws.take() {
+            let _ = ws.close(msg).await;
+        }
+        self.guard = None;
     }
 }
 
 // Broadcast all the incoming game state to the clients.
 // One game handler per game
-pub async fn game_handler(mut host: WebSocketStream<Upgraded>, game_list: GameList) {
-    let mut connections: HashMap<u32, PlayerSink> = HashMap::new();
-    let mut max_players_: u32 = 0;
-
-    let mut accept_players = false;
+pub async fn game_handler(
+    host: WebSocketStream<Upgraded>,
+    game_list: GameList,
+    protocol: Option<Protocol>,
+    guard: Option<ConnGuard>,
+) {
+    let mut host = Host::new(host, protocol, guard);
+    let secret = gen_token();
+    let mut room = Room::default();
 
-    let (tx_to_here, mut rx) = unbounded_channel::<HostComm>();
+    let (tx_to_here, mut rx) = channel::<HostComm>(CONFIG.room_queue);
     let tx_to_here = Arc::new(tx_to_here);
 
-    let mut game_name = "".to_owned();
-
-    info!("A game started.");
-    host.send(to_message(to_state(
-        &game_name,
-        &connections,
-        max_players_,
-        accept_players,
-    )))
-    .await
-    .unwrap();
+    info!("A game started with protocol v{}.", host.version);
+    host.send(room.state(host.version)).await;
 
-    host.send(to_message(Command::To {
+    host.send(Command::To {
         to: vec![1, 2, 3],
+        group: None,
+        audience: false,
+        except: vec![],
         data: vec![1, 2, 3],
-    }))
-    .await
-    .unwrap();
+    })
+    .await;
 
     let mut id: Option<String> = None;
+    let mut ticker = tokio::time::interval(Duration::from_secs(1));
+    let mut lifetime = Lifetime::new();
 
     loop {
         select! {
             event = rx.recv().fuse() => {
                 if let Some(event) = event {
+                    if let HostComm::Join(_) | HostComm::Command(Command::From { .. } | Command::FromStr { .. }) = event {
+                        lifetime.touch();
+                    }
                     match event {
-                        HostComm::Join(mut conn) => {
-                            if accept_players && connections.len() < max_players_.try_into().unwrap() {
-                                host.send(to_message(Command::PlayerJoined{player: conn.id})).await.unwrap();
-                                let _success = connections.insert(conn.id, conn);
-                            } else if (conn.sink.close().await).is_ok() {}
-                            host.send(to_message(to_state(&game_name,&connections, max_players_, accept_players))).await.unwrap();
-                        }
-                        HostComm::Leave(conn) => {
-                            connections.remove(&conn);
-                            host.send(to_message(Command::PlayerLeft { player: conn})).await.unwrap();
-                            host.send(to_message(to_state(&game_name,&connections, max_players_, accept_players))).await.unwrap();
+                        HostComm::Join(PlayerJoin { mut sink, params, admitted }) => {
+                            let resumed = params.resume.as_ref().and_then(|token| room.sessions.get(token).cloned());
+                            if params.role == PlayerRole::Audience {
+                                // Spectators come and go, even once the game started
+                                if room.spectators.len() < room.max_spectators as usize {
+                                    let spectator = sink.id;
+                                    sink.meta = params.meta;
+                                    let _ = admitted.send(spectator);
+                                    host.send(Command::SpectatorJoined { spectator, meta: sink.meta.clone() }).await;
+                                    room.spectators.insert(spectator, sink);
+                                } else {
+                                    sink.close(Some(Error::RoomFull.close_frame()));
+                                }
+                            } else if let Some(player) = resumed {
+                                // Same id as before, a stale socket gets replaced
+                                sink.id = player;
+                                sink.meta = params.meta;
+                                let previous = room.away.remove(&player).map(|a| a.meta)
+                                    .or_else(|| room.connections.get(&player).map(|c| c.meta.clone()));
+                                if let (true, Some(meta)) = (sink.meta.is_empty(), previous) {
+                                    sink.meta = meta;
+                                }
+                                let token = params.resume.unwrap_or_default();
+                                if let Ok(msg) = to_json(Command::Session { player, token }) {
+                                    sink.send(msg);
+                                }
+                                if let Some(old) = room.connections.insert(player, sink) {
+                                    old.close(None);
+                                }
+                                let _ = admitted.send(player);
+                                host.send(Command::PlayerReconnected { player }).await;
+                            } else if room.has_seat() {
+                                sink.meta = params.meta;
+                                let session = params.resume.is_some();
+                                if room.approval {
+                                    let player = sink.id;
+                                    host.send(Command::JoinRequest { player, meta: sink.meta.clone() }).await;
+                                    room.pending.insert(player, Pending { sink, session, admitted });
+                                } else {
+                                    let (player, meta) = room.seat(sink, session, admitted);
+                                    host.send(Command::PlayerJoined { player, meta }).await;
+                                }
+                            } else {
+                                sink.close(Some(Error::RoomFull.close_frame()));
+                            }
+                            host.send(room.state(host.version)).await;
+                        }
+                        HostComm::Leave(player, conn) => {
+                            // A resumed player's old socket may leave after the new one joined
+                            let current = room.connections.get(&player).map(|c| c.conn) == Some(conn);
+                            if let Some(gone) = current.then(|| room.connections.remove(&player)).flatten() {
+                                if room.sessions.values().any(|p| *p == player) {
+                                    room.away.insert(player, Away { since: Instant::now(), meta: gone.meta });
+                                    host.send(Command::PlayerDisconnected { player }).await;
+                                } else {
+                                    room.forget(player);
+                                    host.send(Command::PlayerLeft { player }).await;
+                                }
+                                host.send(room.state(host.version)).await;
+                            } else if room.spectators.get(&player).map(|c| c.conn) == Some(conn) {
+                                room.spectators.remove(&player);
+                                room.forget(player);
+                                host.send(Command::SpectatorLeft { spectator: player }).await;
+                                host.send(room.state(host.version)).await;
+                            } else if room.pending.get(&player).map(|p| p.sink.conn) == Some(conn) {
+                                // Gave up waiting for the host
+                                room.pending.remove(&player);
+                                host.send(Command::JoinCancelled { player }).await;
+                                host.send(room.state(host.version)).await;
+                            }
+                        }
+                        HostComm::Command(Command::From { from, data }) if room.relay_copy(from).is_some() => {
+                            let copy = room.relay_copy(from) == Some(true);
+                            if let Some((to, payload)) = parse_relay(&data) {
+                                let to = room.relay_targets(from, to);
+                                let (_, lagging) = room.deliver(&to, &Message::Binary(payload.to_vec()));
+                                on_lagging(&mut host, &mut room, lagging).await;
+                                let data = copy.then(|| payload.to_vec());
+                                host.send(Command::Relayed { from, to, data }).await;
+                            } else {
+                                host.send(Command::From { from, data }).await;
+                            }
+                        }
+                        HostComm::Command(Command::FromStr { from, data }) if room.relay_copy(from).is_some() => {
+                            let copy = room.relay_copy(from) == Some(true);
+                            if let Some((to, payload)) = parse_relay_str(&data) {
+                                let to = room.relay_targets(from, to);
+                                let (_, lagging) = room.deliver(&to, &Message::Text(payload.to_owned()));
+                                on_lagging(&mut host, &mut room, lagging).await;
+                                let data = copy.then(|| payload.to_owned());
+                                host.send(Command::RelayedStr { from, to, data }).await;
+                            } else {
+                                host.send(Command::FromStr { from, data }).await;
+                            }
                         }
                         HostComm::Command(cmd) => {
-                            host.send(to_message(cmd)).await.unwrap();
+                            host.send(cmd).await;
+                        }
+                        HostComm::Resume(ws, host_secret, protocol, guard) => {
+                            let mut ws = *ws;
+                            if let (Some(key), true) = (id.clone(), same_secret(&host_secret, &secret)) {
+                                info!("The host of {:?} is back.", id);
+                                host.resume(ws, protocol, guard).await;
+                                host.send(Command::PrepareReply { key, secret: secret.clone() }).await;
+                                host.flush().await;
+                                host.send(room.state(host.version)).await;
+                            } else {
+                                let _ = ws.close(Some(Error::InvalidSecret.close_frame())).await;
+                            }
                         }
                     }
                 }
             },
             event = host.next().fuse() => {
-                let cmd = read_command(event);
-                if let Some(cmd) = cmd {
-                    match cmd {
-                        Command::Prepare{max_players, name} => {
-                            if id.is_none() {
-                                max_players_ = max_players;
-                                accept_players = true;
-                                game_name = name.clone();
-                                host.send(to_message(to_state(&game_name,&connections, max_players_, accept_players))).await.unwrap();
-
-                                for _ in 0..4 {
-                                    let val = gen_room_code();
-                                    if !game_list.read().contains_key(&val) {
-                                        id = Some(val);
-                                        break;
-                                    }
-                                }
+                let msg = match event {
+                    Some(Ok(msg)) => msg,
+                    // The rest of the oversized message is still in the socket
+                    Some(Err(WsError::Capacity(e))) => {
+                        info!("The host of {:?} sent too much: {}", id, e);
+                        host.fail(None, ErrorCode::PayloadTooLarge, None).await;
+                        host.close(Some(Error::TooLarge.close_frame())).await;
+                        host.lost();
+                        if id.is_none() {
+                            break;
+                        }
+                        continue;
+                    }
+                    _ => {
+                        // Keep the room for a while if it exists, the host may resume it
+                        info!("The host of {:?} is away.", id);
+                        host.lost();
+                        if id.is_none() {
+                            break;
+                        }
+                        continue;
+                    }
+                };
+                let HostRequest { req_id, command } = match read_command(msg, host.encoding) {
+                    Ok(request) => {
+                        lifetime.touch();
+                        request
+                    }
+                    Err(Rejected { req_id, code, command }) => {
+                        host.fail(req_id, code, command.as_deref()).await;
+                        continue;
+                    }
+                };
+                // Recipients which were not there or could not keep up, reported in the Ack
+                let mut dropped = vec![];
+                let missing: Vec<u32> = match command {
+                    Command::Prepare{max_players, name, encoding, max_spectators, code, code_secret, password, approval} => {
+                        if id.is_none() {
+                            room.approval = approval;
+                            if let Some(encoding) = encoding {
+                                host.encoding = encoding;
+                            }
+                            room.max_players = max_players;
+                            room.max_spectators = max_spectators.min(CONFIG.max_spectators);
+                            room.accept_players = true;
+                            room.name = name.clone();
+                            host.send(room.state(host.version)).await;
 
-                                if let Some(room) = id.clone() {
-                                    info!("The new game id is {:?}.", id);
-                                    host.send(to_message(Command::PrepareReply { key: room.clone() } )).await.unwrap();
-                                    {
-                                        game_list.write().insert(room, GameConfig{
-                                            name: game_name.clone(),
-                                            to_game: tx_to_here.clone(),
-                                        });
-                                    }
-                                } else {
-                                    host.send(to_message(Command::Error { reason: "Failed to find a unique room key".to_owned() })).await.unwrap();
+                            let game = GameConfig {
+                                name: room.name.clone(),
+                                to_game: tx_to_here.clone(),
+                                password: password.filter(|p| !p.is_empty()),
+                                failed_joins: HashMap::new(),
+                                limit: Arc::new(Mutex::new(RateLimit::new(CONFIG.room_msg_rate, CONFIG.room_byte_rate))),
+                            };
+                            match register_room(&game_list, code, code_secret.as_deref(), game) {
+                                Ok(key) => {
+                                    info!("The new game id is {:?}.", key);
+                                    id = Some(key.clone());
+                                    host.send(Command::PrepareReply { key, secret: secret.clone() } ).await;
+                                }
+                                Err(code) => {
+                                    host.fail(req_id, code, Some("prepare")).await;
+                                    continue;
                                 }
-                            } else if let Some(room) = id.clone() {
-                                host.send(to_message(Command::PrepareReply { key: room } )).await.unwrap();
                             }
-                        },
-                        Command::Start => {
-                            if connections.len() <= max_players_.try_into().unwrap() {
-                                accept_players = false;
+                        } else if let Some(key) = id.clone() {
+                            host.send(Command::PrepareReply { key, secret: secret.clone() } ).await;
+                        }
+                        vec![]
+                    },
+                    Command::Start => {
+                        if id.is_none() {
+                            host.fail(req_id, ErrorCode::NotPrepared, Some("start")).await;
+                            continue;
+                        }
+                        if room.started {
+                            host.fail(req_id, ErrorCode::AlreadyStarted, Some("start")).await;
+                            continue;
+                        }
+                        room.started = true;
+                        room.accept_players = false;
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::Admission { open, max_players } => {
+                        if id.is_none() {
+                            host.fail(req_id, ErrorCode::NotPrepared, Some("admission")).await;
+                            continue;
+                        }
+                        // Players above a lowered maximum keep their seat
+                        if let Some(max_players) = max_players {
+                            room.max_players = max_players;
+                        }
+                        room.accept_players = open;
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::Admit { player } => {
+                        let pending = match room.pending.remove(&player) {
+                            Some(pending) => pending,
+                            None => {
+                                host.fail(req_id, ErrorCode::PlayerNotFound, Some("admit")).await;
+                                continue;
                             }
-                            host.send(to_message(to_state(&game_name,&connections, max_players_, accept_players))).await.unwrap();
-                        },
-                        Command::Kick{player} => {
-                            let conn = connections.remove(&player);
-                            if let Some(mut conn) = conn {
-                                conn.sink.close().await.unwrap();
+                        };
+                        if !room.has_seat() {
+                            pending.sink.close(Some(Error::RoomFull.close_frame()));
+                            host.fail(req_id, ErrorCode::RoomFull, Some("admit")).await;
+                            host.send(room.state(host.version)).await;
+                            continue;
+                        }
+                        let (player, meta) = room.seat(pending.sink, pending.session, pending.admitted);
+                        host.send(Command::PlayerJoined { player, meta }).await;
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::Reject { player } => {
+                        match room.pending.remove(&player) {
+                            Some(pending) => pending.sink.close(Some(Error::Rejected.close_frame())),
+                            None => {
+                                host.fail(req_id, ErrorCode::PlayerNotFound, Some("reject")).await;
+                                continue;
                             }
-                            host.send(to_message(to_state(&game_name,&connections, max_players_, accept_players))).await.unwrap();
-                        },
-                        Command::Stop => {
-                            break;
-                        },
-                        Command::To { to, data } => {
-                            let dest: Vec<u32> = if to.is_empty() {
-                                connections.keys().cloned().collect()
+                        }
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::Kick{player} => {
+                        if let Some(pending) = room.pending.remove(&player) {
+                            // A waiting player never joined, kicking it is rejecting it
+                            pending.sink.close(Some(Error::Rejected.close_frame()));
+                        } else if room.is_known(player) {
+                            // Its socket leaves once closed, too late to be reported
+                            let left = if let Some(conn) = room.spectators.remove(&player) {
+                                conn.close(None);
+                                Command::SpectatorLeft { spectator: player }
                             } else {
-                                to
-                            };
-                            for player in dest.iter() {
-                                if let Some(dest) = connections.get_mut(player) {
-                                    dest.sink.send(Message::Binary(data.clone())).await.unwrap();
+                                if let Some(conn) = room.connections.remove(&player) {
+                                    conn.close(None);
                                 }
-                            }
-                        },
-                        Command::ToStr { to, data } => {
-                            let dest: Vec<u32> = if to.is_empty() {
-                                connections.keys().cloned().collect()
-                            } else {
-                                to
+                                Command::PlayerLeft { player }
                             };
-                            for player in dest.iter() {
-                                if let Some(dest) = connections.get_mut(player) {
-                                    dest.sink.send(Message::Text(data.clone())).await.unwrap();
-                                }
+                            room.forget(player);
+                            host.send(left).await;
+                        } else {
+                            host.fail(req_id, ErrorCode::PlayerNotFound, Some("kick")).await;
+                            continue;
+                        }
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::Stop => {
+                        break;
+                    },
+                    Command::To { to, group, audience, except, data } => {
+                        let dest = match room.recipients(to, group, audience, &except) {
+                            Ok(dest) => dest,
+                            Err(code) => {
+                                host.fail(req_id, code, Some("to")).await;
+                                continue;
+                            }
+                        };
+                        let (lost, lagging) = room.deliver(&dest, &Message::Binary(data));
+                        dropped = lost;
+                        on_lagging(&mut host, &mut room, lagging).await;
+                        dest.into_iter().filter(|p| !room.is_connected(*p)).collect()
+                    },
+                    Command::ToStr { to, group, audience, except, data } => {
+                        let dest = match room.recipients(to, group, audience, &except) {
+                            Ok(dest) => dest,
+                            Err(code) => {
+                                host.fail(req_id, code, Some("to_str")).await;
+                                continue;
                             }
-                        },
-                        _ => {},
+                        };
+                        let (lost, lagging) = room.deliver(&dest, &Message::Text(data));
+                        dropped = lost;
+                        on_lagging(&mut host, &mut room, lagging).await;
+                        dest.into_iter().filter(|p| !room.is_connected(*p)).collect()
+                    },
+                    Command::SetGroup { group, players } => {
+                        if !is_group_name(&group) {
+                            host.fail(req_id, ErrorCode::InvalidGroupName, Some("set_group")).await;
+                            continue;
+                        }
+                        let (members, missing): (Vec<u32>, Vec<u32>) = players.into_iter().partition(|p| room.is_known(*p));
+                        room.groups.insert(group, members.into_iter().collect());
+                        host.send(room.state(host.version)).await;
+                        missing
+                    },
+                    Command::AddToGroup { group, players } => {
+                        if !is_group_name(&group) {
+                            host.fail(req_id, ErrorCode::InvalidGroupName, Some("add_to_group")).await;
+                            continue;
+                        }
+                        let (added, missing): (Vec<u32>, Vec<u32>) = players.into_iter().partition(|p| room.is_known(*p));
+                        room.groups.entry(group).or_default().extend(added);
+                        host.send(room.state(host.version)).await;
+                        missing
+                    },
+                    Command::RemoveFromGroup { group, players } => {
+                        let members = match room.groups.get_mut(&group) {
+                            Some(members) => members,
+                            None => {
+                                host.fail(req_id, ErrorCode::GroupNotFound, Some("remove_from_group")).await;
+                                continue;
+                            }
+                        };
+                        for player in players.iter() {
+                            members.remove(player);
+                        }
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::DeleteGroup { group } => {
+                        if room.groups.remove(&group).is_none() {
+                            host.fail(req_id, ErrorCode::GroupNotFound, Some("delete_group")).await;
+                            continue;
+                        }
+                        host.send(room.state(host.version)).await;
+                        vec![]
+                    },
+                    Command::AllowRelay { players, copy } => {
+                        if players.is_empty() {
+                            room.relay_all = Some(copy);
+                        }
+                        let (allowed, missing): (Vec<u32>, Vec<u32>) = players.into_iter().partition(|p| room.connections.contains_key(p));
+                        for player in allowed {
+                            room.relay.insert(player, Some(copy));
+                        }
+                        missing
+                    },
+                    Command::DenyRelay { players } => {
+                        if players.is_empty() {
+                            room.relay_all = None;
+                            room.relay.clear();
+                        }
+                        let (known, missing): (Vec<u32>, Vec<u32>) = players.into_iter().partition(|p| room.is_known(*p));
+                        for player in known {
+                            room.relay.insert(player, None);
+                        }
+                        missing
+                    },
+                    // Only the server sends the other commands
+                    other => {
+                        let name = command_name(&other);
+                        host.fail(req_id, ErrorCode::UnknownCommand, name.as_deref()).await;
+                        continue;
+                    },
+                };
+                host.ack(req_id, missing, dropped).await;
+            },
+            _ = ticker.tick().fuse() => {
+                let expired: Vec<u32> = room.away
+                    .iter()
+                    .filter(|(_, a)| a.since.elapsed() >= CONFIG.resume_grace)
+                    .map(|(player, _)| *player)
+                    .collect();
+                for player in expired.iter() {
+                    room.forget(*player);
+                    host.send(Command::PlayerLeft { player: *player }).await;
+                }
+                if !expired.is_empty() {
+                    host.send(room.state(host.version)).await;
+                }
+                if let Some(since) = host.away_since {
+                    if id.is_none() || since.elapsed() >= CONFIG.host_grace {
+                        info!("The host of {:?} did not come back.", id);
+                        break;
                     }
-                } else {
-                    host.send(to_message(Command::Error{reason: "Invalid message.".to_owned()})).await.unwrap();
+                }
+                match lifetime.check() {
+                    Some(Expiry::Warn(reason, left)) => {
+                        let secs = left.as_secs_f64().ceil() as u32;
+                        host.send(Command::Expiring { reason, secs }).await;
+                    }
+                    Some(Expiry::Close(reason)) => {
+                        info!("Game {:?} expired ({:?}).", id, reason);
+                        break;
+                    }
+                    None => {}
                 }
             },
             complete => {break;}
@@ -239,52 +1219,370 @@ pub async fn game_handler(mut host: WebSocketStream<Upgraded>, game_list: GameLi
         code: CloseCode::Away,
         reason: Cow::Borrowed("The game is done."),
     });
-    if let Ok(_) = host.close(close_msg).await {
-        // Cool
-    }
-    let keys: Vec<_> = connections.keys().cloned().collect();
-    for connection in keys.iter() {
-        let val = connections.remove(connection);
-        if let Some(mut val) = val {
-            if let Ok(_) = val.sink.close().await {
-                // Cool
-            }
-        }
+    host.close(close_msg).await;
+    for (_, val) in room.connections.drain().chain(room.spectators.drain()) {
+        val.close(None);
+    }
+    for (_, pending) in room.pending.drain() {
+        pending.sink.close(Some(Error::RoomClosed.close_frame()));
     }
     {
-        if let Some(room) = id.clone() {
-            game_list.write().remove(&room);
+        if let Some(key) = id.clone() {
+            game_list.write().remove(&key);
         }
     }
 }
 
+// Resolves once the room queue has space, never if there is nothing to send
+async fn room_permit(
+    game_sender: &Sender<HostComm>,
+    waiting: bool,
+) -> Result<Permit<'_, HostComm>, SendError<()>> {
+    if waiting {
+        game_sender.reserve().await
+    } else {
+        pending().await
+    }
+}
+
 // One client handler per client;
-pub async fn client_handler(game_sender: Arc<UnboundedSender<HostComm>>, player: Player) {
+pub async fn client_handler(
+    game_sender: Arc<Sender<HostComm>>,
+    room_limit: Arc<Mutex<RateLimit>>,
+    player: Player,
+    params: JoinParams,
+) {
+    let conn = player.id;
     let (sink, mut stream) = player.ws.split();
 
-    game_sender
-        .send(HostComm::Join(PlayerSink::new(player.id, sink)))
-        .unwrap();
-
-    while let Some(msg) = stream.next().await {
-        if let Ok(msg) = msg {
-            if let Message::Text(str_data) = msg {
-                let _ = game_sender.send(HostComm::Command(Command::FromStr {
-                    from: player.id,
-                    data: str_data,
-                }));
-            } else if let Message::Binary(data) = msg {
-                let _ = game_sender.send(HostComm::Command(Command::From {
-                    from: player.id,
-                    data,
-                }));
-            } else if msg.is_close() {
-                break;
-            }
-        } else {
-            break;
+    let sink = PlayerSink::new(conn, sink);
+    // Fires once the socket fails or the game closes it
+    let mut alive = sink.alive();
+    let outgoing = sink.outgoing();
+    let (admitted, on_admitted) = oneshot::channel();
+    let join = HostComm::Join(PlayerJoin {
+        sink,
+        params,
+        admitted,
+    });
+    if let Err(SendError(HostComm::Join(join))) = game_sender.send(join).await {
+        // The room finished since the player was routed to it
+        join.sink.close(Some(Error::RoomClosed.close_frame()));
+        return;
+    }
+    // The game gives back the player id, which differs from conn when resuming.
+    // A player may leave while the host did not admit it yet, what it sends meanwhile is dropped.
+    // It is pinged meanwhile, not to wait forever on a half-open socket.
+    let mut heartbeat = Heartbeat::new(CONFIG.ping_interval, CONFIG.pong_timeout);
+    let mut on_admitted = on_admitted.fuse();
+    let mut closed = false;
+    let id = loop {
+        select! {
+            id = on_admitted => match id {
+                Ok(id) => break id,
+                Err(_) => return,
+            },
+            msg = async { if closed { pending().await } else { stream.next().await } }.fuse() => match msg {
+                Some(Ok(Message::Pong(data))) => {
+                    heartbeat.pong(&data);
+                }
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => {
+                    closed = true;
+                    let _ = game_sender.send(HostComm::Leave(conn, conn)).await;
+                }
+                Some(Ok(_)) => {}
+            },
+            beat = async { if closed { pending().await } else { heartbeat.tick().await } }.fuse() => match beat {
+                Beat::Ping(payload) => {
+                    if outgoing.try_send(Message::Ping(payload)).is_err() {
+                        heartbeat.skip();
+                    }
+                }
+                Beat::Dead => {
+                    info!("Player {} stopped answering pings while waiting.", conn);
+                    closed = true;
+                    let _ = game_sender.send(HostComm::Leave(conn, conn)).await;
+                }
+            },
+        }
+    };
+    if closed {
+        let _ = game_sender.send(HostComm::Leave(id, conn)).await;
+        return;
+    }
+
+    // Keep reading while the room is busy, the policy decides what to drop
+    let mut queue: VecDeque<Command> = VecDeque::new();
+    let mut dropped: u32 = 0;
+    let mut limit = RateLimit::new(CONFIG.player_msg_rate, CONFIG.player_byte_rate);
+    // Reported once to the host until the player slows down
+    let mut limited = false;
+    loop {
+        let waiting = dropped > 0 || !queue.is_empty();
+        select! {
+            permit = room_permit(&game_sender, waiting).fuse() => {
+                let permit = match permit {
+                    Ok(permit) => permit,
+                    Err(_) => break,
+                };
+                if dropped > 0 {
+                    permit.send(HostComm::Command(Command::Dropped { player: id, count: dropped }));
+                    dropped = 0;
+                } else if let Some(cmd) = queue.pop_front() {
+                    permit.send(HostComm::Command(cmd));
+                }
+            },
+            msg = stream.next().fuse() => {
+                let (len, cmd) = match msg {
+                    Some(Ok(Message::Text(data))) => (data.len(), Command::FromStr { from: id, data }),
+                    Some(Ok(Message::Binary(data))) => (data.len(), Command::From { from: id, data }),
+                    Some(Err(WsError::Capacity(e))) => {
+                        info!("Player {} sent too much: {}", id, e);
+                        let _ = outgoing.try_send(Message::Close(Some(Error::TooLarge.close_frame())));
+                        let _ = game_sender.try_send(HostComm::Command(Command::TooLarge { player: id, code: ErrorCode::PayloadTooLarge }));
+                        break;
+                    }
+                    Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                    Some(Ok(Message::Pong(data))) => {
+                        if let Some(rtt) = heartbeat.pong(&data) {
+                            // Not worth waiting for a busy room
+                            let ms = rtt.as_millis().try_into().unwrap_or(u32::MAX);
+                            let _ = game_sender.try_send(HostComm::Command(Command::Latency { player: id, ms }));
+                        }
+                        continue;
+                    }
+                    Some(Ok(_)) => continue,
+                };
+                let mut wait = limit.wait(len).max(room_limit.lock().wait(len));
+                if !wait.is_zero() {
+                    if !limited {
+                        limited = true;
+                        let _ = game_sender.try_send(HostComm::Command(Command::RateLimited { player: id, code: ErrorCode::RateLimited }));
+                    }
+                    match CONFIG.rate_policy {
+                        RatePolicy::Drop => continue,
+                        RatePolicy::Kick => {
+                            info!("Player {} exceeded its rate limit, kicking.", id);
+                            break;
+                        }
+                        // Stops reading the socket meanwhile
+                        RatePolicy::Throttle => {
+                            while !wait.is_zero() {
+                                tokio::time::sleep(wait).await;
+                                wait = limit.wait(len).max(room_limit.lock().wait(len));
+                            }
+                        }
+                    }
+                } else {
+                    limited = false;
+                }
+                limit.take(len);
+                room_limit.lock().take(len);
+                if queue.len() >= CONFIG.player_queue {
+                    match CONFIG.queue_policy {
+                        QueuePolicy::DropOldest => {
+                            queue.pop_front();
+                        }
+                        QueuePolicy::DropNewest => {
+                            dropped += 1;
+                            continue;
+                        }
+                        QueuePolicy::Disconnect => {
+                            info!("Player {} sent too fast, disconnecting.", id);
+                            break;
+                        }
+                    }
+                    dropped += 1;
+                }
+                queue.push_back(cmd);
+            },
+            beat = heartbeat.tick().fuse() => {
+                match beat {
+                    // Only a ping which was sent is waited for
+                    Beat::Ping(payload) => {
+                        if outgoing.try_send(Message::Ping(payload)).is_err() {
+                            heartbeat.skip();
+                        }
+                    }
+                    Beat::Dead => {
+                        info!("Player {} stopped answering pings.", id);
+                        break;
+                    }
+                }
+            },
+            _ = alive.changed().fuse() => break,
         }
     }
     // If this fails, the game is already finished.
-    game_sender.send(HostComm::Leave(player.id)).unwrap();
+    let _ = game_sender.send(HostComm::Leave(id, conn)).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn relay_str_to_players() {
+        assert_eq!(parse_relay_str("WMBR2,3:hi"), Some((vec![2, 3], "hi")));
+        assert_eq!(parse_relay_str("WMBR2, 3:a:b"), Some((vec![2, 3], "a:b")));
+        assert_eq!(parse_relay_str("WMBR:hi"), Some((vec![], "hi")));
+        assert_eq!(parse_relay_str("WMBR2,,3:"), Some((vec![2, 3], "")));
+    }
+
+    #[test]
+    fn relay_str_invalid() {
+        assert_eq!(parse_relay_str("2,3:hi"), None);
+        assert_eq!(parse_relay_str("WMBR2,3"), None);
+        assert_eq!(parse_relay_str("WMBRa:hi"), None);
+        assert_eq!(parse_relay_str("WMBR-1:hi"), None);
+        assert_eq!(parse_relay_str("wmbr2:hi"), None);
+        assert_eq!(parse_relay_str(""), None);
+        // Chat is not mistaken for an envelope
+        assert_eq!(parse_relay_str(">12:30 meet"), None);
+        assert_eq!(parse_relay_str(">:)"), None);
+    }
+
+    #[test]
+    fn relay_to_players() {
+        let data = b"WMBR\x02\x00\x00\x00\x02\x00\x00\x01\x00hi";
+        assert_eq!(parse_relay(data), Some((vec![2, 256], &b"hi"[..])));
+        assert_eq!(parse_relay(b"WMBR\x00"), Some((vec![], &b""[..])));
+        assert_eq!(parse_relay(b"WMBR\x00data"), Some((vec![], &b"data"[..])));
+    }
+
+    #[test]
+    fn relay_truncated() {
+        assert_eq!(parse_relay(b""), None);
+        assert_eq!(parse_relay(b"WMB"), None);
+        assert_eq!(parse_relay(b"WMBR"), None);
+        assert_eq!(parse_relay(b"WMBR\x01\x00\x00\x00"), None);
+        assert_eq!(parse_relay(b"WMBR\x02\x00\x00\x00\x02"), None);
+        assert_eq!(parse_relay(b"XMBR\x00"), None);
+    }
+
+    fn game() -> GameConfig {
+        let (to_game, _) = channel(1);
+        GameConfig {
+            to_game: Arc::new(to_game),
+            name: "test".to_owned(),
+            password: None,
+            failed_joins: HashMap::new(),
+            limit: Arc::new(Mutex::new(RateLimit::new(0, 0))),
+        }
+    }
+
+    // With the default format, four letters from A to Z
+    #[test]
+    fn room_codes() {
+        assert!(is_room_code("PIZZ"));
+        assert!(!is_room_code("pizz"));
+        assert!(!is_room_code("PIZ"));
+        assert!(!is_room_code("PIZZA"));
+        assert!(!is_room_code("P1ZZ"));
+        assert!(!is_room_code("CREATE"));
+        for _ in 0..100 {
+            if let Some(code) = gen_room_code() {
+                assert!(is_room_code(&code));
+            }
+        }
+    }
+
+    #[test]
+    fn register_requested_codes() {
+        let games: GameList = Default::default();
+        let register = |code: &str| register_room(&games, Some(code.to_owned()), None, game());
+        assert_eq!(register("pizz"), Ok("PIZZ".to_owned()));
+        assert_eq!(register("PIZZ"), Err(ErrorCode::RoomCodeTaken));
+        assert_eq!(register("Pizz"), Err(ErrorCode::RoomCodeTaken));
+        assert_eq!(register("PIZZA"), Err(ErrorCode::InvalidRoomCode));
+        assert_eq!(register("P1ZZ"), Err(ErrorCode::InvalidRoomCode));
+        assert_eq!(register(""), Err(ErrorCode::InvalidRoomCode));
+        assert!(games.read().contains_key("PIZZ"));
+    }
+
+    #[test]
+    fn register_random_codes() {
+        let games: GameList = Default::default();
+        let mut registered = 0;
+        for _ in 0..20 {
+            if let Ok(code) = register_room(&games, None, None, game()) {
+                assert!(is_room_code(&code));
+                registered += 1;
+            }
+        }
+        assert_eq!(games.read().len(), registered);
+        assert!(registered > 0);
+    }
+
+    #[test]
+    fn group_names() {
+        assert!(is_group_name("red"));
+        assert!(is_group_name("team_1-b"));
+        assert!(is_group_name(&"g".repeat(MAX_GROUP_NAME_LEN)));
+        assert!(!is_group_name(""));
+        assert!(!is_group_name("red team"));
+        assert!(!is_group_name("équipe"));
+        assert!(!is_group_name(&"g".repeat(MAX_GROUP_NAME_LEN + 1)));
+    }
+
+    #[test]
+    fn forget_prunes_groups() {
+        let mut room = Room::default();
+        room.groups.insert("red".to_owned(), [1, 2].into());
+        room.groups.insert("blue".to_owned(), [2].into());
+        room.groups.insert("green".to_owned(), [3].into());
+        room.sessions.insert("token".to_owned(), 2);
+        room.relay.insert(2, Some(true));
+        room.away.insert(
+            2,
+            Away {
+                since: Instant::now(),
+                meta: PlayerMeta::new(),
+            },
+        );
+        room.forget(2);
+        assert_eq!(room.groups["red"], [1].into());
+        // Emptied groups stay, the host may still add players to them
+        assert!(room.groups["blue"].is_empty());
+        assert_eq!(room.groups["green"], [3].into());
+        assert!(room.sessions.is_empty());
+        assert!(room.relay.is_empty());
+        assert!(!room.is_known(2));
+        assert_eq!(
+            room.recipients(vec![], Some("blue".to_owned()), false, &[]),
+            Ok(vec![])
+        );
+        assert_eq!(
+            room.recipients(vec![1], Some("reed".to_owned()), false, &[]),
+            Err(ErrorCode::GroupNotFound)
+        );
+    }
+
+    fn recipients(to: Vec<u32>, group: bool, audience: bool, except: &[u32]) -> Vec<u32> {
+        let players = [1, 2, 3];
+        let spectators = [10, 11];
+        let members: HashSet<u32> = [3, 10].into();
+        let members = group.then_some(&members);
+        let spectators = audience.then(|| spectators.iter());
+        pick_recipients(players.iter(), to, members, spectators, except)
+    }
+
+    #[test]
+    fn recipients_empty_to() {
+        assert_eq!(recipients(vec![], false, false, &[]), vec![1, 2, 3]);
+        assert_eq!(recipients(vec![], false, false, &[2]), vec![1, 3]);
+        // Only the group or the audience then
+        assert_eq!(recipients(vec![], true, false, &[]), vec![3, 10]);
+        assert_eq!(recipients(vec![], false, true, &[]), vec![10, 11]);
+        assert_eq!(recipients(vec![], true, true, &[11]), vec![3, 10]);
+    }
+
+    #[test]
+    fn recipients_listed() {
+        assert_eq!(recipients(vec![2, 2], false, false, &[]), vec![2]);
+        assert_eq!(recipients(vec![2, 7], false, false, &[7]), vec![2]);
+        assert_eq!(recipients(vec![2], true, false, &[]), vec![2, 3, 10]);
+        assert_eq!(recipients(vec![2], false, true, &[10]), vec![2, 11]);
+        assert_eq!(recipients(vec![1], true, true, &[1, 3]), vec![10, 11]);
+    }
 }
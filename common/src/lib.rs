@@ -1,3 +1,6 @@
 pub mod comm;
+pub mod config;
 pub mod entry;
+pub mod error;
 pub mod game;
+pub mod limit;
@@ -0,0 +1,208 @@
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv6Addr};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::config::CONFIG;
+
+// Open websockets per client address
+static CONNECTIONS: Lazy<Mutex<HashMap<IpAddr, u32>>> = Lazy::new(Default::default);
+
+/// Holds `capacity` tokens, refilled over `period`.
+/// A capacity of zero is unlimited.
+#[derive(Debug)]
+pub struct TokenBucket {
+    capacity: f64,
+    // Tokens per second
+    rate: f64,
+    tokens: f64,
+    last: Instant,
+}
+
+impl TokenBucket {
+    /// `rate` tokens per second, holding a second worth of them.
+    pub fn new(rate: u32) -> Self {
+        Self::per(rate, Duration::from_secs(1))
+    }
+
+    pub fn per(capacity: u32, period: Duration) -> Self {
+        let capacity = capacity as f64;
+        Self {
+            capacity,
+            rate: capacity / period.as_secs_f64().max(f64::EPSILON),
+            tokens: capacity,
+            last: Instant::now(),
+        }
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last = now;
+    }
+
+    // More than the bucket holds waits for a full bucket
+    fn needed(&self, n: f64) -> f64 {
+        n.min(self.capacity)
+    }
+
+    /// How long until `n` tokens are there, zero if they already are.
+    pub fn wait(&mut self, n: f64) -> Duration {
+        if self.capacity == 0.0 {
+            return Duration::ZERO;
+        }
+        self.refill();
+        let missing = self.needed(n) - self.tokens;
+        if missing <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing / self.rate)
+        }
+    }
+
+    pub fn take(&mut self, n: f64) {
+        if self.capacity != 0.0 {
+            self.tokens -= self.needed(n);
+        }
+    }
+
+    /// Whether the bucket refilled completely, as if it was never used.
+    pub fn is_full(&mut self) -> bool {
+        self.refill();
+        self.tokens >= self.capacity
+    }
+}
+
+/// Messages and bytes per second, of a player or of a whole room.
+#[derive(Debug)]
+pub struct RateLimit {
+    messages: TokenBucket,
+    bytes: TokenBucket,
+}
+
+impl RateLimit {
+    pub fn new(messages: u32, bytes: u32) -> Self {
+        Self {
+            messages: TokenBucket::new(messages),
+            bytes: TokenBucket::new(bytes),
+        }
+    }
+
+    /// How long until a message of `len` bytes fits, zero if it does.
+    pub fn wait(&mut self, len: usize) -> Duration {
+        self.messages.wait(1.0).max(self.bytes.wait(len as f64))
+    }
+
+    pub fn take(&mut self, len: usize) {
+        self.messages.take(1.0);
+        self.bytes.take(len as f64);
+    }
+}
+
+/// The address limits are kept for. An IPv6 client usually has a whole /64 to pick from,
+/// so the prefix counts as one address.
+pub fn address_key(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V4(_) => ip,
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => IpAddr::V6(Ipv6Addr::from(u128::from(v6) & !(u64::MAX as u128))),
+        },
+    }
+}
+
+/// Counts a websocket of the client address for as long as it lives.
+#[derive(Debug)]
+pub struct ConnGuard(IpAddr);
+
+impl ConnGuard {
+    /// None once the address has `max_conns_per_ip` websockets open.
+    pub fn new(ip: IpAddr) -> Option<Self> {
+        let mut conns = CONNECTIONS.lock();
+        let count = conns.entry(ip).or_default();
+        if CONFIG.max_conns_per_ip != 0 && *count >= CONFIG.max_conns_per_ip {
+            return None;
+        }
+        *count += 1;
+        Some(ConnGuard(ip))
+    }
+}
+
+impl Drop for ConnGuard {
+    fn drop(&mut self) {
+        let mut conns = CONNECTIONS.lock();
+        if let Some(count) = conns.get_mut(&self.0) {
+            *count -= 1;
+            if *count == 0 {
+                conns.remove(&self.0);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn address_keys() {
+        let key = |ip: &str| address_key(ip.parse().unwrap());
+        assert_eq!(key("1.2.3.4"), "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(key("::ffff:1.2.3.4"), "1.2.3.4".parse::<IpAddr>().unwrap());
+        assert_eq!(
+            key("2001:db8:1:2:3:4:5:6"),
+            "2001:db8:1:2::".parse::<IpAddr>().unwrap()
+        );
+        assert_eq!(key("2001:db8:1:2::1"), key("2001:db8:1:2:ffff::9"));
+        assert_ne!(key("2001:db8:1:2::1"), key("2001:db8:1:3::1"));
+    }
+
+    #[test]
+    fn zero_capacity_is_unlimited() {
+        let mut bucket = TokenBucket::new(0);
+        for _ in 0..1000 {
+            assert_eq!(bucket.wait(1000.0), Duration::ZERO);
+            bucket.take(1000.0);
+        }
+        assert!(bucket.is_full());
+
+        let mut limit = RateLimit::new(0, 0);
+        limit.take(1 << 20);
+        assert_eq!(limit.wait(1 << 20), Duration::ZERO);
+    }
+
+    #[test]
+    fn bucket_runs_out() {
+        let mut bucket = TokenBucket::per(2, Duration::from_secs(60));
+        assert!(bucket.is_full());
+        assert_eq!(bucket.wait(1.0), Duration::ZERO);
+        bucket.take(1.0);
+        assert!(!bucket.is_full());
+        assert_eq!(bucket.wait(1.0), Duration::ZERO);
+        bucket.take(1.0);
+        // A token comes back every 30 seconds
+        let wait = bucket.wait(1.0);
+        assert!(wait > Duration::from_secs(29) && wait <= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn bucket_waits_for_full_at_most() {
+        let mut bucket = TokenBucket::per(2, Duration::from_secs(60));
+        // More than it holds only needs a full bucket
+        assert_eq!(bucket.wait(10.0), Duration::ZERO);
+        bucket.take(10.0);
+        let wait = bucket.wait(10.0);
+        assert!(wait > Duration::from_secs(59) && wait <= Duration::from_secs(60));
+    }
+
+    #[test]
+    fn rate_limit_bytes() {
+        let mut limit = RateLimit::new(100, 10);
+        assert_eq!(limit.wait(10), Duration::ZERO);
+        limit.take(10);
+        assert!(limit.wait(1) > Duration::ZERO);
+    }
+}
@@ -1,6 +1,9 @@
 pub mod comm;
+pub mod config;
 pub mod entry;
+pub mod error;
 pub mod game;
+pub mod limit;
 
 #[cfg(feature = "tls")]
 pub mod tls;